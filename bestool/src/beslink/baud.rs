@@ -0,0 +1,160 @@
+use crate::beslink::{
+    check_status, send_message, sync, BESLinkError, BesTransport, ChipProfile, FlashGetId, FlashId,
+    MessageTypes, SetBaudRate, Stage, Timeouts,
+};
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Asks the running programmer to change speed, follows it on the host side and checks it still answers.
+// If it refuses, or cannot be heard at the new rate, the host goes back to the chip's default rate and that is returned.
+// Experimental: the command is only known from BES's headers and has never been seen in a capture
+pub fn negotiate_baud_rate(
+    transport: &mut dyn BesTransport,
+    chip: &ChipProfile,
+    baud_rate: u32,
+    timeouts: &Timeouts,
+) -> Result<u32, BESLinkError> {
+    if !transport.can_change_baud_rate() {
+        warn!(
+            "This connection cannot change its baud rate, ignoring --baud and staying at {}",
+            chip.baud_rate
+        );
+        return Ok(chip.baud_rate);
+    }
+    warn!(
+        "--baud is experimental: the set baud rate command has not been seen in a capture of the vendor tool, and real programmers may not support it"
+    );
+    info!("Asking the programmer to switch to {baud_rate} baud");
+    send_message(transport, SetBaudRate { baud_rate }.to_message())?;
+    let accepted = sync(
+        transport,
+        MessageTypes::DeviceCommand,
+        &timeouts.deadline(Stage::Command),
+    )
+    .and_then(|response| check_status(&response, 0x00));
+    if let Err(e) = accepted {
+        // Nothing changed on the device, so it is still on the default rate
+        warn!(
+            "Programmer did not accept {baud_rate} baud ({e}), staying at {}",
+            chip.baud_rate
+        );
+        return Ok(chip.baud_rate);
+    }
+    // Give the device a moment to reprogram its uart
+    std::thread::sleep(Duration::from_millis(10));
+    transport.set_baud_rate(baud_rate)?;
+    let _ = transport.clear_buffers();
+    match check_link(transport, timeouts) {
+        Ok(_) => {
+            info!("Now programming at {baud_rate} baud");
+            Ok(baud_rate)
+        }
+        Err(e) => {
+            warn!(
+                "Lost the programmer at {baud_rate} baud ({e}), falling back to {}",
+                chip.baud_rate
+            );
+            transport.set_baud_rate(chip.baud_rate)?;
+            let _ = transport.clear_buffers();
+            check_link(transport, timeouts)?;
+            Ok(chip.baud_rate)
+        }
+    }
+}
+
+// Any short command with a known reply will do to prove both ends agree on the rate
+fn check_link(transport: &mut dyn BesTransport, timeouts: &Timeouts) -> Result<(), BESLinkError> {
+    send_message(transport, FlashGetId.to_message())?;
+    let response = sync(
+        transport,
+        MessageTypes::FlashCommand,
+        &timeouts.deadline(Stage::Command),
+    )?;
+    FlashId::from_message(&response)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::baud::negotiate_baud_rate;
+    use crate::beslink::memory_transport::MemoryTransport;
+    use crate::beslink::read_flash::read_flash_data;
+    use crate::beslink::simulator::{DeviceSimulator, SIM_FLASH_BASE};
+    use crate::beslink::{
+        find_chip, helper_sync_and_load_programmer, ProgrammingOptions, Timeouts,
+    };
+    use std::time::Duration;
+
+    fn quick_timeouts() -> Timeouts {
+        Timeouts {
+            command: Duration::from_millis(50),
+            ..Timeouts::default()
+        }
+    }
+
+    #[test]
+    fn test_negotiates_higher_rate() {
+        let timeouts = quick_timeouts();
+        let chip = find_chip("BES2300").unwrap();
+        let mut sim = DeviceSimulator::new();
+        sim.accepts_baud_change = true;
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        assert_eq!(
+            negotiate_baud_rate(&mut sim, chip, 3_000_000, &timeouts).unwrap(),
+            3_000_000
+        );
+        assert_eq!(sim.device_baud_rate, 3_000_000);
+        read_flash_data(&mut sim, SIM_FLASH_BASE, 0x100, &timeouts).unwrap();
+    }
+    #[test]
+    fn test_stays_at_default_when_refused() {
+        let timeouts = quick_timeouts();
+        let chip = find_chip("BES2300").unwrap();
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        assert_eq!(
+            negotiate_baud_rate(&mut sim, chip, 3_000_000, &timeouts).unwrap(),
+            chip.baud_rate
+        );
+        read_flash_data(&mut sim, SIM_FLASH_BASE, 0x100, &timeouts).unwrap();
+    }
+    #[test]
+    fn test_skipped_when_the_rate_is_fixed() {
+        let chip = find_chip("BES2300").unwrap();
+        let mut transport = MemoryTransport::new();
+        transport.fixed_baud_rate = true;
+        assert_eq!(
+            negotiate_baud_rate(&mut transport, chip, 3_000_000, &quick_timeouts()).unwrap(),
+            chip.baud_rate
+        );
+        assert!(transport.written().is_empty());
+    }
+    #[test]
+    fn test_sync_switches_to_requested_rate() {
+        let timeouts = quick_timeouts();
+        let options = ProgrammingOptions {
+            baud_rate: Some(3_000_000),
+            ..ProgrammingOptions::default()
+        };
+        let mut sim = DeviceSimulator::new();
+        sim.accepts_baud_change = true;
+        helper_sync_and_load_programmer(&mut sim, &options, &timeouts).unwrap();
+        assert_eq!(sim.device_baud_rate, 3_000_000);
+        read_flash_data(&mut sim, SIM_FLASH_BASE, 0x100, &timeouts).unwrap();
+    }
+    #[test]
+    fn test_sync_falls_back_when_refused() {
+        let timeouts = quick_timeouts();
+        let chip = find_chip("BES2300").unwrap();
+        let options = ProgrammingOptions {
+            baud_rate: Some(3_000_000),
+            ..ProgrammingOptions::default()
+        };
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &options, &timeouts).unwrap();
+        assert_eq!(sim.device_baud_rate, chip.baud_rate);
+        read_flash_data(&mut sim, SIM_FLASH_BASE, 0x100, &timeouts).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+// Finds the parts of a flash region that are not erased (0xFF) as it is read,
+// so a whole chip can be checked without holding the dump in memory
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NonBlankRange {
+    pub start: usize,
+    pub end: usize,
+    pub first_byte: u8,
+}
+
+#[derive(Default)]
+pub struct BlankChecker {
+    current: Option<NonBlankRange>,
+}
+
+impl BlankChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Data has to be pushed in address order; returns the ranges that ended inside it
+    pub fn push(&mut self, address: usize, data: &[u8]) -> Vec<NonBlankRange> {
+        let mut finished = vec![];
+        for (i, b) in data.iter().enumerate() {
+            let pos = address + i;
+            match (&mut self.current, *b == 0xFF) {
+                (Some(range), false) if range.end == pos => range.end = pos + 1,
+                (Some(_), _) => {
+                    finished.extend(self.current.take());
+                    if *b != 0xFF {
+                        self.current = Some(NonBlankRange {
+                            start: pos,
+                            end: pos + 1,
+                            first_byte: *b,
+                        });
+                    }
+                }
+                (None, false) => {
+                    self.current = Some(NonBlankRange {
+                        start: pos,
+                        end: pos + 1,
+                        first_byte: *b,
+                    })
+                }
+                (None, true) => {}
+            }
+        }
+        finished
+    }
+
+    pub fn finish(&mut self) -> Option<NonBlankRange> {
+        self.current.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::blank_check::{BlankChecker, NonBlankRange};
+
+    #[test]
+    fn test_ranges_span_pushes() {
+        let mut checker = BlankChecker::new();
+        assert!(checker.push(0x100, &[0xFF, 0x12, 0x00]).is_empty());
+        assert_eq!(
+            checker.push(0x103, &[0x34, 0xFF, 0xFF, 0x56]),
+            vec![NonBlankRange {
+                start: 0x101,
+                end: 0x104,
+                first_byte: 0x12
+            }]
+        );
+        assert_eq!(
+            checker.finish(),
+            Some(NonBlankRange {
+                start: 0x106,
+                end: 0x107,
+                first_byte: 0x56
+            })
+        );
+        assert!(checker.push(0x200, &[0xFF; 64]).is_empty());
+        assert_eq!(checker.finish(), None);
+    }
+}
@@ -1,81 +1,180 @@
-use crate::beslink::{send_message, sync, BESLinkError, BesMessage, MessageTypes, BES_SYNC};
-use serialport::SerialPort;
-use std::io::Write;
+use crate::beslink::{
+    check_status, send_message, sync, BESLinkError, BesMessage, BesTransport, ChipProfile,
+    MessageTypes, ProgrammerImage, ProgrammerInfo, Stage, Timeouts,
+};
+use std::path::{Path, PathBuf};
 use tracing::error;
 use tracing::info;
-//Embed the bin file for future
-const PROGRAMMER_BINARY: &[u8; 75928] = include_bytes!("../../../programmer.bin");
+use tracing::warn;
+const PROGRAMMER_ENV: &str = "BESTOOL_PROGRAMMER";
+
+fn find_programmer(
+    chip: &ChipProfile,
+    selected: Option<&ProgrammerImage>,
+) -> Result<ProgrammerImage, BESLinkError> {
+    find_programmer_in(
+        selected,
+        std::env::var_os(PROGRAMMER_ENV).map(PathBuf::from),
+        chip.programmer,
+        config_programmer_path(),
+    )
+}
+
+// --programmer first, then $BESTOOL_PROGRAMMER, then the chip's embedded blob, then one in the config dir.
+// Takes each source as an argument so builds without an embedded blob can be tested
+fn find_programmer_in(
+    selected: Option<&ProgrammerImage>,
+    env_path: Option<PathBuf>,
+    embedded: Option<&[u8]>,
+    config_path: Option<PathBuf>,
+) -> Result<ProgrammerImage, BESLinkError> {
+    if let Some(image) = selected {
+        return Ok(image.clone());
+    }
+    if let Some(path) = env_path {
+        return read_programmer(&path);
+    }
+    if let Some(blob) = embedded {
+        return ProgrammerImage::parse(blob);
+    }
+    match config_path {
+        Some(path) if path.is_file() => read_programmer(&path),
+        config_path => Err(BESLinkError::ProgrammerNotFound { config_path }),
+    }
+}
+
+pub fn read_programmer(path: &Path) -> Result<ProgrammerImage, BESLinkError> {
+    info!("Using programmer {}", path.display());
+    ProgrammerImage::parse(&std::fs::read(path)?)
+}
+
+// $XDG_CONFIG_HOME/bestool/programmer.bin, falling back to %APPDATA% and then ~/.config
+fn config_programmer_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| std::env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("bestool").join("programmer.bin"))
+}
 
 pub fn load_programmer_runtime_binary_blob(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
+    chip: &ChipProfile,
+    programmer: Option<&ProgrammerImage>,
+    timeouts: &Timeouts,
 ) -> Result<(), BESLinkError> {
-    let preload_setup_message = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::StartProgrammer,
-        payload: vec![
-            0x00, 0x0C, //??
-            0x1C, 0x06, 0x01, 0x20, // Possibly load address in ram?
-            0x78, 0x24, 0x01, 0x00, // Suspect length
-            0x8A, 0xD7, 0xB9, 0x9E,
-        ],
-        checksum: 0x4A,
-    };
-    info!("Start Message {:X?}", preload_setup_message.to_vec());
-    send_message(serial_port, preload_setup_message)?;
-    let response = sync(serial_port, MessageTypes::StartProgrammer)?;
-    if response.payload[0] != 0x00 {
-        return Err(BESLinkError::BadResponseCode {
-            failed_packet: response.to_vec(),
-            got: response.payload[0],
-            wanted: 0,
-        });
+    let image = find_programmer(chip, programmer)?;
+    if image.load_address != chip.programmer_load_address {
+        warn!(
+            "Programmer is built to run at 0x{:08X}, but a {} loads it at 0x{:08X}",
+            image.load_address, chip.name, chip.programmer_load_address
+        );
     }
-    let programmer_leader = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::ProgrammerRunning,
-        payload: vec![
-            0xA2, 0x03, 0x00, 0x00, 0x00, 0x48, 0x05, 0x45, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00,
-        ],
-        checksum: 0x00,
-    };
-    send_message(serial_port, programmer_leader)?;
-    match serial_port.write_all(&PROGRAMMER_BINARY[0x428..PROGRAMMER_BINARY.len() - 4]) {
+    let start_request = image.start_request();
+    info!(
+        "Programmer is 0x{:X} bytes loaded at 0x{:08X}, CRC 0x{:08X}",
+        start_request.len, start_request.load_address, start_request.crc32
+    );
+    let preload_setup_message = start_request.to_message();
+    send_message(transport, preload_setup_message)?;
+    let response = sync(
+        transport,
+        MessageTypes::StartProgrammer,
+        &timeouts.deadline(Stage::Sync),
+    )?;
+    check_status(&response, 0x00)?;
+    // The leader frame is followed by the raw programmer image, without any framing.
+    // What its payload means is not known, so it is sent as captured whichever programmer is used
+    let programmer_leader = BesMessage::new(
+        MessageTypes::ProgrammerRunning,
+        0xA2,
+        vec![0x00, 0x00, 0x00],
+    );
+    send_message(transport, programmer_leader)?;
+    match transport.write_all(&image.code) {
         Ok(_) => {}
         Err(e) => {
             error!("Failed to write the programmer binary {:?}", e);
             return Err(BESLinkError::from(e));
         }
     }
-    let response = sync(serial_port, MessageTypes::ProgrammerRunning)?;
-    if response.payload != vec![0xA2, 0x01, 0x20] {
-        return Err(BESLinkError::BadResponseCode {
-            failed_packet: response.to_vec(),
-            got: response.payload[2],
-            wanted: 0x20,
-        });
-    }
+    let response = sync(
+        transport,
+        MessageTypes::ProgrammerRunning,
+        &timeouts.deadline(Stage::Sync),
+    )?;
+    check_status(&response, 0x20)?;
 
     Ok(())
 }
 pub fn start_programmer_runtime_binary_blob(
-    serial_port: &mut Box<dyn SerialPort>,
-) -> Result<BesMessage, BESLinkError> {
-    let preload_setup_message = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::ProgrammerStart,
-        payload: vec![0x01, 0x00],
-        checksum: 0xEB,
-    };
-    send_message(serial_port, preload_setup_message)?;
+    transport: &mut dyn BesTransport,
+    timeouts: &Timeouts,
+) -> Result<ProgrammerInfo, BESLinkError> {
+    let preload_setup_message = BesMessage::new(MessageTypes::ProgrammerStart, 0x01, vec![]);
+    send_message(transport, preload_setup_message)?;
     info!("Sent start programmer message");
-    let resp = sync(serial_port, MessageTypes::ProgrammerInit)?;
-    if resp.payload != vec![0x00, 0x06, 0x03, 0x01, 0x00, 0x90, 0x00, 0x00] {
-        return Err(BESLinkError::BadResponseCode {
-            failed_packet: resp.to_vec(),
-            got: resp.payload[0],
-            wanted: 0x0,
-        });
+    let resp = sync(
+        transport,
+        MessageTypes::ProgrammerInit,
+        &timeouts.deadline(Stage::Sync),
+    )?;
+    ProgrammerInfo::from_message(&resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::bootloader::find_programmer_in;
+    use crate::beslink::{BESLinkError, ProgrammerImage};
+
+    const PROGRAMMER_BLOB: &[u8] = include_bytes!("../../../programmer.bin");
+
+    // As a build without the embedded blob would look for one
+    #[test]
+    fn test_find_programmer_without_embedded_blob() {
+        let dir = std::env::temp_dir().join(format!("bestool-programmer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let blob_path = dir.join("from-env.bin");
+        std::fs::write(&blob_path, PROGRAMMER_BLOB).unwrap();
+        let config_path = dir.join("bestool").join("programmer.bin");
+        let expected = ProgrammerImage::parse(PROGRAMMER_BLOB).unwrap();
+
+        // --programmer wins over everything else
+        let selected = ProgrammerImage {
+            load_address: 0x2000_0000,
+            code: vec![0; 0x10],
+        };
+        let image = find_programmer_in(
+            Some(&selected),
+            Some(blob_path.clone()),
+            Some(PROGRAMMER_BLOB),
+            Some(config_path.clone()),
+        );
+        assert_eq!(image.unwrap(), selected);
+
+        // $BESTOOL_PROGRAMMER
+        let image = find_programmer_in(
+            None,
+            Some(blob_path.clone()),
+            None,
+            Some(config_path.clone()),
+        );
+        assert_eq!(image.unwrap(), expected);
+
+        // Nothing in the config dir either
+        match find_programmer_in(None, None, None, Some(config_path.clone())) {
+            Err(BESLinkError::ProgrammerNotFound {
+                config_path: Some(path),
+            }) => assert_eq!(path, config_path),
+            other => panic!("Expected ProgrammerNotFound, got {other:?}"),
+        }
+
+        // The config dir
+        std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+        std::fs::rename(&blob_path, &config_path).unwrap();
+        let image = find_programmer_in(None, None, None, Some(config_path.clone()));
+        assert_eq!(image.unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-    Ok(resp)
 }
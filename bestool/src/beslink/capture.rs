@@ -0,0 +1,378 @@
+use crate::beslink::BesTransport;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::{LineWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// Capture files are plain text, one event per line so they can be read (and diffed) by hand:
+//   # bestool capture v1
+//   0.012345 TX BE 50 00 01 01 EF
+//   0.013001 RX BE 50 00 03 02 00 01 EB
+//   0.020000 ERR TimedOut
+//   1.500000 BAUD 2000000
+// Timestamps are seconds since the port was opened; TX/RX is from the point of view of the pc
+
+const CAPTURE_HEADER: &str = "# bestool capture v1";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CaptureEvent {
+    Tx(Vec<u8>),
+    Rx(Vec<u8>),
+    ReadError(ErrorKind),
+    Baud(u32),
+}
+
+impl CaptureEvent {
+    fn to_line(&self, elapsed: Duration) -> String {
+        let body = match self {
+            CaptureEvent::Tx(data) => format!("TX {}", hex_bytes(data)),
+            CaptureEvent::Rx(data) => format!("RX {}", hex_bytes(data)),
+            CaptureEvent::ReadError(kind) => format!("ERR {kind:?}"),
+            CaptureEvent::Baud(baud) => format!("BAUD {baud}"),
+        };
+        format!("{:.6} {}\n", elapsed.as_secs_f64(), body)
+    }
+
+    fn from_line(line: &str) -> Option<(Duration, CaptureEvent)> {
+        let mut parts = line.split_whitespace();
+        let elapsed = Duration::from_secs_f64(parts.next()?.parse().ok()?);
+        let event = match parts.next()? {
+            "TX" => CaptureEvent::Tx(parse_hex_bytes(parts)?),
+            "RX" => CaptureEvent::Rx(parse_hex_bytes(parts)?),
+            "ERR" => CaptureEvent::ReadError(match parts.next()? {
+                "TimedOut" => ErrorKind::TimedOut,
+                "BrokenPipe" => ErrorKind::BrokenPipe,
+                "UnexpectedEof" => ErrorKind::UnexpectedEof,
+                _ => ErrorKind::Other,
+            }),
+            "BAUD" => CaptureEvent::Baud(parts.next()?.parse().ok()?),
+            _ => return None,
+        };
+        Some((elapsed, event))
+    }
+}
+
+fn hex_bytes(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_hex_bytes<'a>(parts: impl Iterator<Item = &'a str>) -> Option<Vec<u8>> {
+    parts.map(|p| u8::from_str_radix(p, 16).ok()).collect()
+}
+
+pub fn load_capture(path: &Path) -> std::io::Result<Vec<(Duration, CaptureEvent)>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut events = vec![];
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match CaptureEvent::from_line(line) {
+            Some(event) => events.push(event),
+            None => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Bad capture line {}: {line}", line_no + 1),
+                ))
+            }
+        }
+    }
+    Ok(events)
+}
+
+// Wraps another transport and logs every byte in both directions to a capture file
+pub struct RecordingTransport {
+    inner: Box<dyn BesTransport>,
+    capture: LineWriter<File>,
+    start: Instant,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Box<dyn BesTransport>, capture_path: &Path) -> std::io::Result<Self> {
+        let mut capture = LineWriter::new(File::create(capture_path)?);
+        writeln!(capture, "{CAPTURE_HEADER}")?;
+        Ok(RecordingTransport {
+            inner,
+            capture,
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, event: CaptureEvent) {
+        let line = event.to_line(self.start.elapsed());
+        if let Err(e) = self.capture.write_all(line.as_bytes()) {
+            warn!("Failed to write to capture file {:?}", e);
+        }
+    }
+}
+
+impl Read for RecordingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                if n > 0 {
+                    self.record(CaptureEvent::Rx(buf[..n].to_vec()));
+                }
+                Ok(n)
+            }
+            Err(e) => {
+                self.record(CaptureEvent::ReadError(e.kind()));
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Write for RecordingTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(CaptureEvent::Tx(buf[..n].to_vec()));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl BesTransport for RecordingTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)?;
+        self.record(CaptureEvent::Baud(baud_rate));
+        Ok(())
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        self.inner.can_change_baud_rate()
+    }
+
+    fn clear_buffers(&mut self) -> std::io::Result<()> {
+        self.inner.clear_buffers()
+    }
+
+    fn set_dtr(&mut self, level: bool) -> std::io::Result<()> {
+        self.inner.set_dtr(level)
+    }
+
+    fn set_rts(&mut self, level: bool) -> std::io::Result<()> {
+        self.inner.set_rts(level)
+    }
+}
+
+// Plays the device side of a capture back; reads return the recorded RX bytes and errors in order,
+// writes are checked against the recorded TX bytes so it is obvious where a replay stops matching
+pub struct ReplayTransport {
+    events: Vec<CaptureEvent>,
+    position: usize,
+    offset: usize,
+    diverged: bool,
+}
+
+impl ReplayTransport {
+    pub fn open(capture_path: &Path) -> std::io::Result<Self> {
+        let events = load_capture(capture_path)?
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect();
+        Ok(ReplayTransport::new(events))
+    }
+
+    pub fn new(events: Vec<CaptureEvent>) -> Self {
+        ReplayTransport {
+            events,
+            position: 0,
+            offset: 0,
+            diverged: false,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.position += 1;
+        self.offset = 0;
+    }
+
+    fn diverge(&mut self, why: String) {
+        if !self.diverged {
+            warn!(
+                "Replay diverged from capture at event {}: {why}",
+                self.position
+            );
+            self.diverged = true;
+        }
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Baud changes do not affect what is read back, so step over any in the way
+        while let Some(CaptureEvent::Baud(_)) = self.events.get(self.position) {
+            self.advance();
+        }
+        match self.events.get(self.position).cloned() {
+            Some(CaptureEvent::Rx(data)) => {
+                let n = buf.len().min(data.len() - self.offset);
+                buf[..n].copy_from_slice(&data[self.offset..self.offset + n]);
+                self.offset += n;
+                if self.offset == data.len() {
+                    self.advance();
+                }
+                Ok(n)
+            }
+            Some(CaptureEvent::ReadError(kind)) => {
+                self.advance();
+                Err(std::io::Error::from(kind))
+            }
+            // The pc has not sent what it sent last time yet, so the device has nothing to say
+            Some(CaptureEvent::Tx(_)) => Err(std::io::Error::from(ErrorKind::TimedOut)),
+            _ => Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "End of replay capture",
+            )),
+        }
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for b in buf {
+            loop {
+                match self.events.get(self.position).cloned() {
+                    Some(CaptureEvent::Tx(data)) => {
+                        if data[self.offset] != *b {
+                            self.diverge(format!(
+                                "sent 0x{b:02X} but capture has 0x{:02X}",
+                                data[self.offset]
+                            ));
+                        }
+                        self.offset += 1;
+                        if self.offset == data.len() {
+                            self.advance();
+                        }
+                        break;
+                    }
+                    Some(CaptureEvent::Baud(_)) => self.advance(),
+                    Some(_) => {
+                        // The device side was not fully read last time around; drop it to stay aligned
+                        self.diverge("wrote before reading everything the device sent".to_owned());
+                        self.advance();
+                    }
+                    None => {
+                        self.diverge("wrote past the end of the capture".to_owned());
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BesTransport for ReplayTransport {
+    fn set_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Resets are not recorded, so a session made with --reset replays without one
+    fn set_dtr(&mut self, _level: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_rts(&mut self, _level: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::capture::{
+        load_capture, CaptureEvent, RecordingTransport, ReplayTransport,
+    };
+    use crate::beslink::simulator::DeviceSimulator;
+    use crate::beslink::{
+        helper_sync_and_load_programmer, BesTransport, ProgrammingOptions, Timeouts,
+    };
+    use std::io::ErrorKind;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn test_event_line_round_trip() {
+        let events = vec![
+            CaptureEvent::Tx(vec![0xBE, 0x50, 0x00, 0x01, 0x01, 0xEF]),
+            CaptureEvent::Rx(vec![0xBE, 0x50]),
+            CaptureEvent::ReadError(ErrorKind::TimedOut),
+            CaptureEvent::Baud(921_600),
+        ];
+        for event in events {
+            let line = event.to_line(Duration::from_micros(1_500_123));
+            assert!(line.starts_with("1.500123 "));
+            let (elapsed, parsed) = CaptureEvent::from_line(line.trim()).unwrap();
+            assert_eq!(elapsed.as_micros(), 1_500_123);
+            assert_eq!(parsed, event);
+        }
+    }
+    #[test]
+    fn test_record_then_replay_session() {
+        let capture_path = std::env::temp_dir().join("bestool_test_record_then_replay.txt");
+        {
+            let sim: Box<dyn BesTransport> = Box::new(DeviceSimulator::new());
+            let mut recorder = RecordingTransport::new(sim, &capture_path).unwrap();
+            helper_sync_and_load_programmer(
+                &mut recorder,
+                &ProgrammingOptions::default(),
+                &Timeouts::default(),
+            )
+            .unwrap();
+        }
+        let events = load_capture(&capture_path).unwrap();
+        assert!(events
+            .iter()
+            .any(|(_, e)| matches!(e, CaptureEvent::Tx(d) if d[..2] == [0xBE, 0x53])));
+
+        // Same flow against the capture instead of the device gives the same result
+        let mut replay = ReplayTransport::open(&capture_path).unwrap();
+        helper_sync_and_load_programmer(
+            &mut replay,
+            &ProgrammingOptions::default(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        assert!(!replay.diverged);
+        let _ = std::fs::remove_file(capture_path);
+    }
+    #[test]
+    fn test_replay_flags_divergence() {
+        let mut replay = ReplayTransport::new(vec![
+            CaptureEvent::Tx(vec![0xBE, 0x50, 0x00, 0x01, 0x01, 0xEF]),
+            CaptureEvent::Rx(vec![0xBE]),
+        ]);
+        replay
+            .write_all(&[0xBE, 0x50, 0x00, 0x01, 0x02, 0xEE])
+            .unwrap();
+        assert!(replay.diverged);
+        let mut buf = [0; 4];
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(
+            replay.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use crate::beslink::{BESLinkError, BES_FLASH_BASE, BES_PROGRAMMING_BAUDRATE};
+use tracing::warn;
+
+// The blob is BES copyright, so builds that get redistributed leave it out with --no-default-features.
+// Tests always have it, as the simulator needs a programmer to load
+#[cfg(any(feature = "embedded-programmer", test))]
+const BES2300_PROGRAMMER: Option<&[u8]> = Some(include_bytes!("../../../programmer.bin"));
+#[cfg(not(any(feature = "embedded-programmer", test)))]
+const BES2300_PROGRAMMER: Option<&[u8]> = None;
+
+// What differs between BES parts as far as programming them goes
+#[derive(Debug, PartialEq)]
+pub struct ChipProfile {
+    pub name: &'static str,
+    pub flash_base: usize,
+    // RAM address the programmer has to be built to run from
+    pub programmer_load_address: u32,
+    pub programmer: Option<&'static [u8]>,
+    pub baud_rate: u32,
+    // Version in the ROM's sync, which is how the chip is recognised
+    pub rom_version: u16,
+    // Version the programmer reports once it is running
+    pub programmer_version: u16,
+}
+
+// Only parts whose values have been captured from the vendor tool belong here; the first is the default
+const CHIP_PROFILES: &[ChipProfile] = &[ChipProfile {
+    name: "BES2300",
+    flash_base: BES_FLASH_BASE,
+    programmer_load_address: 0x2001_061C,
+    programmer: BES2300_PROGRAMMER,
+    baud_rate: BES_PROGRAMMING_BAUDRATE,
+    rom_version: 0x0100,
+    programmer_version: 0x0103,
+}];
+
+pub fn find_chip(name: &str) -> Option<&'static ChipProfile> {
+    CHIP_PROFILES
+        .iter()
+        .find(|chip| chip.name.eq_ignore_ascii_case(name))
+}
+
+pub fn chip_names() -> Vec<&'static str> {
+    CHIP_PROFILES.iter().map(|chip| chip.name).collect()
+}
+
+// The ROM has to be synced with before it says what it is, so without --chip this is the default's rate
+pub fn chip_baud_rate(forced: Option<&ChipProfile>) -> u32 {
+    forced.map_or(CHIP_PROFILES[0].baud_rate, |chip| chip.baud_rate)
+}
+
+// Anything not recognised is refused rather than guessed at, as loading a programmer built for another part
+// puts it at the wrong RAM address; --chip can still force a profile
+pub fn resolve_chip(
+    rom_version: u16,
+    forced: Option<&'static ChipProfile>,
+) -> Result<&'static ChipProfile, BESLinkError> {
+    if let Some(chip) = forced {
+        if chip.rom_version != rom_version {
+            warn!(
+                "ROM version 0x{:04X} is not the 0x{:04X} expected from a {}",
+                rom_version, chip.rom_version, chip.name
+            );
+        }
+        return Ok(chip);
+    }
+    CHIP_PROFILES
+        .iter()
+        .find(|chip| chip.rom_version == rom_version)
+        .ok_or(BESLinkError::UnknownChip { rom_version })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::chip::{find_chip, resolve_chip};
+    use crate::beslink::BESLinkError;
+
+    #[test]
+    fn test_chip_lookup() {
+        let chip = find_chip("bes2300").unwrap();
+        assert_eq!(chip.name, "BES2300");
+        assert_eq!(resolve_chip(0x0100, None).unwrap(), chip);
+        // Only the BES2300 has a profile, anything else has to be named with --chip
+        assert_eq!(resolve_chip(0x0999, Some(chip)).unwrap(), chip);
+        assert!(matches!(
+            resolve_chip(0x0999, None),
+            Err(BESLinkError::UnknownChip {
+                rom_version: 0x0999
+            })
+        ));
+        assert!(find_chip("BES9999").is_none());
+    }
+}
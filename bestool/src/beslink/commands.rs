@@ -0,0 +1,446 @@
+use crate::beslink::{BESLinkError, BesMessage, MessageTypes};
+
+// Typed versions of the requests we know how to make and the replies we know how to read.
+// The sequence numbers are the ones the vendor tool uses; the device only echoes them back.
+
+const FLASH_CMD_GET_ID: u8 = 0x11;
+const FLASH_CMD_GET_UNIQUE_ID: u8 = 0x12;
+const FLASH_CMD_BURN_COMMIT: u8 = 0x22;
+const DEVICE_CMD_REBOOT: u8 = 0xF1;
+// Named in BES's download tool headers next to reboot, but not yet seen in a capture
+const DEVICE_CMD_SET_BAUD_RATE: u8 = 0xF4;
+// Magic carried by the commit; matches the first word of the programmer blob
+const BURN_COMMIT_MAGIC: [u8; 4] = [0x1C, 0xEC, 0x57, 0xBE];
+// The erase/burn is always done in buffers of this size
+const ERASE_BURN_BUFFER_SIZE: u32 = 0x8000;
+
+pub const BURN_ACK_OK: u8 = 0x60;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EraseBurnStart {
+    pub addr: u32,
+    pub len: u32,
+}
+
+impl EraseBurnStart {
+    pub fn to_message(self) -> BesMessage {
+        let mut payload = vec![];
+        payload.extend(self.addr.to_le_bytes());
+        payload.extend(self.len.to_le_bytes());
+        payload.extend(ERASE_BURN_BUFFER_SIZE.to_le_bytes());
+        BesMessage::new(MessageTypes::EraseBurnStart, 0x05, payload)
+    }
+}
+
+// Tells the ROM where the programmer image goes and how to check it arrived intact
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StartProgrammer {
+    pub load_address: u32,
+    pub len: u32,
+    pub crc32: u32,
+}
+
+impl StartProgrammer {
+    pub fn to_message(self) -> BesMessage {
+        let mut payload = vec![];
+        payload.extend(self.load_address.to_le_bytes());
+        payload.extend(self.len.to_le_bytes());
+        payload.extend(self.crc32.to_le_bytes());
+        BesMessage::new(MessageTypes::StartProgrammer, 0x00, payload)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FlashGetId;
+
+impl FlashGetId {
+    pub fn to_message(self) -> BesMessage {
+        BesMessage::new(MessageTypes::FlashCommand, 0x02, vec![FLASH_CMD_GET_ID])
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FlashGetUniqueId;
+
+impl FlashGetUniqueId {
+    pub fn to_message(self) -> BesMessage {
+        BesMessage::new(
+            MessageTypes::FlashCommand,
+            0x03,
+            vec![FLASH_CMD_GET_UNIQUE_ID],
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FlashBurnCommit {
+    pub addr: u32,
+}
+
+impl FlashBurnCommit {
+    pub fn to_message(self) -> BesMessage {
+        let mut payload = vec![FLASH_CMD_BURN_COMMIT];
+        payload.extend(self.addr.to_le_bytes());
+        payload.extend(BURN_COMMIT_MAGIC);
+        BesMessage::new(MessageTypes::FlashCommand, 0x06, payload)
+    }
+}
+
+// The reply is followed by len bytes of raw memory content, outside of any frame
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MemoryRead {
+    pub addr: u32,
+    pub len: u32,
+}
+
+impl MemoryRead {
+    pub fn to_message(self) -> BesMessage {
+        let mut payload = vec![];
+        payload.extend(self.addr.to_le_bytes());
+        payload.extend(self.len.to_le_bytes());
+        BesMessage::new(MessageTypes::FlashRead, 0x05, payload)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Reboot;
+
+impl Reboot {
+    pub fn to_message(self) -> BesMessage {
+        BesMessage::new(MessageTypes::DeviceCommand, 0x00, vec![DEVICE_CMD_REBOOT])
+    }
+}
+
+// Acknowledged at the current rate; the programmer switches after the reply
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SetBaudRate {
+    pub baud_rate: u32,
+}
+
+impl SetBaudRate {
+    pub fn to_message(self) -> BesMessage {
+        let mut payload = vec![DEVICE_CMD_SET_BAUD_RATE];
+        payload.extend(self.baud_rate.to_le_bytes());
+        BesMessage::new(MessageTypes::DeviceCommand, 0x00, payload)
+    }
+}
+
+// JEDEC ID as reported by the flash chip
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FlashId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8,
+}
+
+impl FlashId {
+    // Most parts encode their size as a power of two in the capacity byte (0x16 is 4MiB)
+    pub fn size(&self) -> Option<usize> {
+        match self.capacity {
+            0x10..=0x1F => Some(1 << self.capacity),
+            _ => None,
+        }
+    }
+    pub fn from_message(msg: &BesMessage) -> Result<Self, BESLinkError> {
+        let data = expect_payload(msg, MessageTypes::FlashCommand, 4)?;
+        check_status(msg, 0x00)?;
+        Ok(FlashId {
+            manufacturer: data[1],
+            memory_type: data[2],
+            capacity: data[3],
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FlashUniqueId {
+    pub id: Vec<u8>,
+}
+
+impl FlashUniqueId {
+    pub fn from_message(msg: &BesMessage) -> Result<Self, BESLinkError> {
+        let data = expect_payload(msg, MessageTypes::FlashCommand, 17)?;
+        check_status(msg, 0x00)?;
+        Ok(FlashUniqueId {
+            id: data[1..].to_vec(),
+        })
+    }
+}
+
+// Sent by the ROM when it boots (state 0) and again to confirm the host sync (state 2)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SyncInfo {
+    pub state: u8,
+    pub version: u16,
+}
+
+pub const SYNC_STATE_CONFIRMED: u8 = 0x02;
+
+impl SyncInfo {
+    pub fn from_message(msg: &BesMessage) -> Result<Self, BESLinkError> {
+        let data = expect_payload(msg, MessageTypes::Sync, 3)?;
+        Ok(SyncInfo {
+            state: data[0],
+            version: u16::from_le_bytes([data[1], data[2]]),
+        })
+    }
+}
+
+// Sent by the programmer once it is up and running
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ProgrammerInfo {
+    pub version: u16,
+    // Only reported; it does not match the erase size of any known flash part, see flash_sector_size
+    pub sector_size: u32,
+}
+
+impl ProgrammerInfo {
+    pub fn from_message(msg: &BesMessage) -> Result<Self, BESLinkError> {
+        let data = expect_payload(msg, MessageTypes::ProgrammerInit, 6)?;
+        Ok(ProgrammerInfo {
+            version: u16::from_le_bytes([data[0], data[1]]),
+            sector_size: u32::from_le_bytes([data[2], data[3], data[4], data[5]]),
+        })
+    }
+}
+
+// Reply to each flash chunk; status is BURN_ACK_OK when the chunk was burnt
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BurnAck {
+    pub seq: u8,
+    pub status: u8,
+    pub block: u16,
+}
+
+impl BurnAck {
+    pub fn from_message(msg: &BesMessage) -> Result<Self, BESLinkError> {
+        let data = expect_payload(msg, MessageTypes::FlashBurnData, 3)?;
+        Ok(BurnAck {
+            seq: msg.seq(),
+            status: data[0],
+            block: u16::from_le_bytes([data[1], data[2]]),
+        })
+    }
+}
+
+// Most replies are just a single status byte
+pub fn check_status(msg: &BesMessage, wanted: u8) -> Result<(), BESLinkError> {
+    match msg.payload().first() {
+        Some(&got) if got == wanted => Ok(()),
+        Some(&got) => Err(BESLinkError::BadResponseCode {
+            failed_packet: msg.to_vec(),
+            got,
+            wanted,
+        }),
+        None => Err(BESLinkError::BadPayloadLength {
+            failed_packet: msg.to_vec(),
+            got: 0,
+            wanted: 1,
+        }),
+    }
+}
+
+fn expect_payload(
+    msg: &BesMessage,
+    type1: MessageTypes,
+    len: usize,
+) -> Result<&[u8], BESLinkError> {
+    if msg.type1() != type1 {
+        return Err(BESLinkError::UnexpectedMessageType {
+            failed_packet: msg.to_vec(),
+            got: msg.type1(),
+            wanted: type1,
+        });
+    }
+    if msg.payload().len() != len {
+        return Err(BESLinkError::BadPayloadLength {
+            failed_packet: msg.to_vec(),
+            got: msg.payload().len(),
+            wanted: len,
+        });
+    }
+    Ok(msg.payload())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::commands::{
+        check_status, BurnAck, EraseBurnStart, FlashBurnCommit, FlashGetId, FlashGetUniqueId,
+        FlashId, FlashUniqueId, MemoryRead, ProgrammerInfo, Reboot, SetBaudRate, StartProgrammer,
+        SyncInfo, BURN_ACK_OK,
+    };
+    use crate::beslink::{BESLinkError, BesMessage};
+
+    // Encodes the request, parses it back, and checks it carries what the captured frame did.
+    // The vendor tool counts its sequence numbers up where we use fixed ones, so those are not compared
+    fn assert_round_trips_to(request: BesMessage, captured: &[u8]) {
+        let parsed = BesMessage::parse(&request.to_vec()).unwrap();
+        assert_eq!(parsed, request);
+        let captured = BesMessage::parse(captured).unwrap();
+        assert_eq!(parsed.type1(), captured.type1());
+        assert_eq!(parsed.payload(), captured.payload());
+    }
+
+    // Replies are parsed from the captured bytes, which they must serialise back to
+    fn parse_captured(captured: &[u8]) -> BesMessage {
+        let msg = BesMessage::parse(captured).unwrap();
+        assert_eq!(msg.to_vec(), captured);
+        msg
+    }
+
+    // Frames captured from the vendor tool, see Docs/Notes.md
+    #[test]
+    fn test_requests_round_trip_to_captured_frames() {
+        let erase = EraseBurnStart {
+            addr: 0x3C00_0000,
+            len: 0xD_0000,
+        };
+        assert_round_trips_to(
+            erase.to_message(),
+            &[
+                0xBE, 0x61, 0x07, 0x0C, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x00, 0x0D, 0x00, 0x00, 0x80,
+                0x00, 0x00, 0x04,
+            ],
+        );
+        assert_round_trips_to(
+            FlashGetId.to_message(),
+            &[0xBE, 0x65, 0x02, 0x01, 0x11, 0xC8],
+        );
+        assert_round_trips_to(
+            FlashGetUniqueId.to_message(),
+            &[0xBE, 0x65, 0x03, 0x01, 0x12, 0xC6],
+        );
+        let commit = FlashBurnCommit { addr: 0x3C00_0000 };
+        assert_round_trips_to(
+            commit.to_message(),
+            &[
+                0xBE, 0x65, 0x08, 0x09, 0x22, 0x00, 0x00, 0x00, 0x3C, 0x1C, 0xEC, 0x57, 0xBE, 0x50,
+            ],
+        );
+        let read = MemoryRead {
+            addr: 0x3C0F_E000,
+            len: 0x1000,
+        };
+        assert_round_trips_to(
+            read.to_message(),
+            &[
+                0xBE, 0x03, 0x05, 0x08, 0x00, 0xE0, 0x0F, 0x3C, 0x00, 0x10, 0x00, 0x00, 0xF6,
+            ],
+        );
+        let start = StartProgrammer {
+            load_address: 0x2001_05DC,
+            len: 0x1_32DC,
+            crc32: 0x0CE8_A7C0,
+        };
+        let captured_start = [
+            0xBE, 0x53, 0x00, 0x0C, 0xDC, 0x05, 0x01, 0x20, 0xDC, 0x32, 0x01, 0x00, 0xC0, 0xA7,
+            0xE8, 0x0C, 0x76,
+        ];
+        assert_round_trips_to(start.to_message(), &captured_start);
+        assert_eq!(start.to_message().to_vec(), captured_start);
+
+        // Neither of these has been captured, so only their encoding can be checked
+        assert_eq!(Reboot.to_message().payload(), [0xF1]);
+        let set_baud = SetBaudRate {
+            baud_rate: 3_000_000,
+        };
+        assert_eq!(
+            set_baud.to_message().payload(),
+            [0xF4, 0xC0, 0xC6, 0x2D, 0x00]
+        );
+    }
+    #[test]
+    fn test_responses_decode_from_captured_frames() {
+        let msg = parse_captured(&[0xBE, 0x65, 0x02, 0x04, 0x00, 0xC8, 0x60, 0x16, 0x98]);
+        assert_eq!(
+            FlashId::from_message(&msg).unwrap(),
+            FlashId {
+                manufacturer: 0xC8,
+                memory_type: 0x60,
+                capacity: 0x16
+            }
+        );
+        assert_eq!(
+            FlashId::from_message(&msg).unwrap().size(),
+            Some(4 * 1024 * 1024)
+        );
+        let msg = parse_captured(&[
+            0xBE, 0x65, 0x03, 0x11, 0x00, 0x30, 0x31, 0x32, 0x33, 0x39, 0x0A, 0x88, 0x4A, 0x41,
+            0x53, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x5F,
+        ]);
+        assert_eq!(
+            FlashUniqueId::from_message(&msg).unwrap().id,
+            [
+                0x30, 0x31, 0x32, 0x33, 0x39, 0x0A, 0x88, 0x4A, 0x41, 0x53, 0xFF, 0xFF, 0xFF, 0xFF,
+                0xFF, 0xFF
+            ]
+        );
+        let msg = parse_captured(&[
+            0xBE, 0x60, 0x00, 0x06, 0x03, 0x01, 0x00, 0x90, 0x00, 0x00, 0x47,
+        ]);
+        assert_eq!(
+            ProgrammerInfo::from_message(&msg).unwrap(),
+            ProgrammerInfo {
+                version: 0x0103,
+                sector_size: 0x9000
+            }
+        );
+        let msg = parse_captured(&[0xBE, 0x62, 0xC2, 0x03, 0x60, 0x01, 0x00, 0xB9]);
+        assert_eq!(
+            BurnAck::from_message(&msg).unwrap(),
+            BurnAck {
+                seq: 0xC2,
+                status: BURN_ACK_OK,
+                block: 1
+            }
+        );
+        let msg = parse_captured(&[0xBE, 0x50, 0x00, 0x03, 0x00, 0x00, 0x01, 0xED]);
+        assert_eq!(
+            SyncInfo::from_message(&msg).unwrap(),
+            SyncInfo {
+                state: 0x00,
+                version: 0x0100
+            }
+        );
+        let msg = parse_captured(&[0xBE, 0x50, 0x00, 0x03, 0x02, 0x00, 0x01, 0xEB]);
+        assert_eq!(
+            SyncInfo::from_message(&msg).unwrap(),
+            SyncInfo {
+                state: 0x02,
+                version: 0x0100
+            }
+        );
+        for captured in [
+            [0xBE, 0x61, 0x07, 0x01, 0x00, 0xD8],
+            [0xBE, 0x65, 0x08, 0x01, 0x00, 0xD3],
+            [0xBE, 0x53, 0x00, 0x01, 0x00, 0xED],
+        ] {
+            assert!(check_status(&parse_captured(&captured), 0x00).is_ok());
+        }
+    }
+    #[test]
+    fn test_bad_responses_are_reported() {
+        let ack = BesMessage::parse(&[0xBE, 0x62, 0xC2, 0x03, 0x60, 0x01, 0x00, 0xB9]).unwrap();
+        assert!(matches!(
+            FlashId::from_message(&ack),
+            Err(BESLinkError::UnexpectedMessageType { .. })
+        ));
+        assert!(matches!(
+            check_status(&ack, 0x00),
+            Err(BESLinkError::BadResponseCode {
+                got: 0x60,
+                wanted: 0x00,
+                ..
+            })
+        ));
+        let short = BesMessage::parse(&[0xBE, 0x60, 0x00, 0x01, 0x03, 0xDD]).unwrap();
+        assert!(matches!(
+            ProgrammerInfo::from_message(&short),
+            Err(BESLinkError::BadPayloadLength {
+                got: 1,
+                wanted: 6,
+                ..
+            })
+        ));
+    }
+}
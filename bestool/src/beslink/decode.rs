@@ -0,0 +1,361 @@
+use crate::beslink::message::calculate_message_checksum;
+use crate::beslink::{MessageTypes, BES_SYNC};
+use std::collections::{HashMap, VecDeque};
+
+// Splits captured uart traffic back into BES frames and describes what each one means, using what is
+// known about the protocol from Docs/Notes.md. Some frames are followed by raw unframed data (the
+// programmer upload, flash burn chunks and flash read dumps); those are reported as a single block so
+// the parser does not try to find frames inside them.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    Tx,
+    Rx,
+    Unknown,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodedItem {
+    Frame {
+        direction: Direction,
+        offset: usize,
+        frame: Vec<u8>,
+        checksum_ok: bool,
+        description: String,
+    },
+    RawData {
+        direction: Direction,
+        offset: usize,
+        length: usize,
+        what: &'static str,
+    },
+    Garbage {
+        direction: Direction,
+        offset: usize,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Default)]
+struct Channel {
+    buffer: Vec<u8>,
+    // Stream offset of buffer[0]
+    offset: usize,
+    // Raw bytes still to come after the last frame, and what they are
+    raw_remaining: usize,
+    raw_what: &'static str,
+    raw_start: usize,
+}
+
+#[derive(Default)]
+pub struct FrameDecoder {
+    channels: HashMap<u8, Channel>,
+    programmer_length: Option<usize>,
+    pending_read_lengths: VecDeque<usize>,
+    flash_commands: HashMap<u8, u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    // Feed more bytes seen in one direction; returns everything that could be fully decoded so far
+    pub fn push(&mut self, direction: Direction, data: &[u8]) -> Vec<DecodedItem> {
+        let key = direction as u8;
+        let mut channel = self.channels.remove(&key).unwrap_or_default();
+        channel.buffer.extend_from_slice(data);
+        let mut items = vec![];
+        loop {
+            if channel.raw_remaining > 0 {
+                let n = channel.raw_remaining.min(channel.buffer.len());
+                channel.buffer.drain(..n);
+                channel.offset += n;
+                channel.raw_remaining -= n;
+                if channel.raw_remaining > 0 {
+                    break;
+                }
+                items.push(DecodedItem::RawData {
+                    direction,
+                    offset: channel.raw_start,
+                    length: channel.offset - channel.raw_start,
+                    what: channel.raw_what,
+                });
+            }
+            let start = channel
+                .buffer
+                .iter()
+                .position(|b| *b == BES_SYNC)
+                .unwrap_or(channel.buffer.len());
+            if start > 0 {
+                items.push(DecodedItem::Garbage {
+                    direction,
+                    offset: channel.offset,
+                    data: channel.buffer.drain(..start).collect(),
+                });
+                channel.offset += start;
+            }
+            if channel.buffer.len() < 4 {
+                break;
+            }
+            let frame_len = 5 + channel.buffer[3] as usize;
+            if channel.buffer.len() < frame_len {
+                break;
+            }
+            let frame: Vec<u8> = channel.buffer[..frame_len].to_vec();
+            let checksum_ok =
+                calculate_message_checksum(&frame[..frame_len - 1]) == frame[frame_len - 1];
+            if !checksum_ok && matches!(MessageTypes::from(frame[1]), MessageTypes::Unknown(_)) {
+                // Almost certainly not a frame at all, step past this sync byte and look again
+                items.push(DecodedItem::Garbage {
+                    direction,
+                    offset: channel.offset,
+                    data: channel.buffer.drain(..1).collect(),
+                });
+                channel.offset += 1;
+                continue;
+            }
+            channel.buffer.drain(..frame_len);
+            let offset = channel.offset;
+            channel.offset += frame_len;
+            let description = self.describe_frame(&frame, &mut channel);
+            items.push(DecodedItem::Frame {
+                direction,
+                offset,
+                frame,
+                checksum_ok,
+                description,
+            });
+        }
+        self.channels.insert(key, channel);
+        items
+    }
+
+    // Anything left over that never formed a complete frame
+    pub fn finish(&mut self) -> Vec<DecodedItem> {
+        let mut items = vec![];
+        for direction in [Direction::Tx, Direction::Rx, Direction::Unknown] {
+            if let Some(channel) = self.channels.get_mut(&(direction as u8)) {
+                if channel.raw_remaining > 0 {
+                    items.push(DecodedItem::RawData {
+                        direction,
+                        offset: channel.raw_start,
+                        length: channel.offset - channel.raw_start,
+                        what: "truncated raw data",
+                    });
+                    channel.raw_remaining = 0;
+                }
+                if !channel.buffer.is_empty() {
+                    items.push(DecodedItem::Garbage {
+                        direction,
+                        offset: channel.offset,
+                        data: std::mem::take(&mut channel.buffer),
+                    });
+                }
+            }
+        }
+        items
+    }
+
+    fn expect_raw(channel: &mut Channel, length: usize, what: &'static str) {
+        channel.raw_remaining = length;
+        channel.raw_what = what;
+        channel.raw_start = channel.offset;
+    }
+
+    fn describe_frame(&mut self, frame: &[u8], channel: &mut Channel) -> String {
+        let seq = frame[2];
+        let data = &frame[4..frame.len() - 1];
+        let type1 = MessageTypes::from(frame[1]);
+        if let MessageTypes::Unknown(raw) = type1 {
+            return format!("UNKNOWN type 0x{raw:02X} seq=0x{seq:02X} data={data:02X?}");
+        }
+        let details = match (type1, data.len()) {
+            (MessageTypes::Sync, 3) => format!(
+                "state={} version=0x{:04X}",
+                data[0],
+                u16::from_le_bytes([data[1], data[2]])
+            ),
+            (MessageTypes::Sync, 1) => format!("ack 0x{:02X}", data[0]),
+            (MessageTypes::StartProgrammer, 12) => {
+                let length = le_u32(&data[4..8]) as usize;
+                self.programmer_length = Some(length);
+                format!(
+                    "load address=0x{:08X} length=0x{length:X} crc32=0x{:08X}",
+                    le_u32(&data[0..4]),
+                    le_u32(&data[8..12])
+                )
+            }
+            (MessageTypes::ProgrammerRunning, 3) => {
+                if let Some(length) = self.programmer_length.take() {
+                    Self::expect_raw(channel, length, "programmer image");
+                }
+                "programmer upload follows".to_owned()
+            }
+            (MessageTypes::ProgrammerRunning, 1) => format!("upload status 0x{:02X}", data[0]),
+            (MessageTypes::ProgrammerStart, 0) => "run programmer".to_owned(),
+            (MessageTypes::ProgrammerInit, 6) => format!(
+                "programmer version=0x{:04X} sector size=0x{:X}",
+                u16::from_le_bytes([data[0], data[1]]),
+                le_u32(&data[2..6])
+            ),
+            (MessageTypes::FlashCommand, _) => self.describe_flash_command(seq, data),
+            (MessageTypes::EraseBurnStart, 12) => format!(
+                "erase/burn start address=0x{:08X} length=0x{:X} block=0x{:X}",
+                le_u32(&data[0..4]),
+                le_u32(&data[4..8]),
+                le_u32(&data[8..12])
+            ),
+            (MessageTypes::FlashBurnData, 11) => {
+                let length = le_u32(&data[0..4]) as usize;
+                Self::expect_raw(channel, length, "flash burn data");
+                format!(
+                    "burn chunk={} length=0x{length:X} crc32=0x{:08X}",
+                    u16::from_le_bytes([data[8], data[9]]),
+                    le_u32(&data[4..8])
+                )
+            }
+            (MessageTypes::FlashBurnData, 3) => format!(
+                "burn ack status=0x{:02X} block={}",
+                data[0],
+                u16::from_le_bytes([data[1], data[2]])
+            ),
+            (MessageTypes::FlashRead, 8) => {
+                self.pending_read_lengths
+                    .push_back(le_u32(&data[4..8]) as usize);
+                format!(
+                    "read address=0x{:08X} length=0x{:X}",
+                    le_u32(&data[0..4]),
+                    le_u32(&data[4..8])
+                )
+            }
+            (MessageTypes::FlashRead, _) => {
+                if let Some(length) = self.pending_read_lengths.pop_front() {
+                    Self::expect_raw(channel, length, "flash read data");
+                }
+                format!("read reply {data:02X?}")
+            }
+            (MessageTypes::DeviceCommand, 1) if data[0] == 0xF1 => "reboot".to_owned(),
+            (_, 1) => format!("status 0x{:02X}", data[0]),
+            _ => format!("data={data:02X?}"),
+        };
+        format!("{type1:?} seq=0x{seq:02X} {details}")
+    }
+
+    fn describe_flash_command(&mut self, seq: u8, data: &[u8]) -> String {
+        // Replies echo the sequence number of the request, which is how we know what they answer
+        match (data.first(), self.flash_commands.get(&seq)) {
+            (Some(0x11), _) if data.len() == 1 => {
+                self.flash_commands.insert(seq, 0x11);
+                "FLASH_CMD_GET_ID".to_owned()
+            }
+            (Some(0x12), _) if data.len() == 1 => {
+                self.flash_commands.insert(seq, 0x12);
+                "FLASH_CMD_GET_UNIQUE_ID".to_owned()
+            }
+            (Some(0x22), _) if data.len() == 9 => {
+                self.flash_commands.insert(seq, 0x22);
+                format!("burn commit address=0x{:08X}", le_u32(&data[1..5]))
+            }
+            (Some(status), Some(0x11)) if data.len() == 4 => format!(
+                "flash ID status=0x{status:02X} id={:02X}-{:02X}-{:02X}",
+                data[1], data[2], data[3]
+            ),
+            (Some(status), Some(0x12)) => format!(
+                "flash unique ID status=0x{status:02X} id={}",
+                data[1..]
+                    .iter()
+                    .map(|b| format!("{b:02X}"))
+                    .collect::<String>()
+            ),
+            (Some(status), _) if data.len() == 1 => format!("status 0x{status:02X}"),
+            _ => format!("data={data:02X?}"),
+        }
+    }
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::decode::{DecodedItem, Direction, FrameDecoder};
+
+    fn descriptions(items: &[DecodedItem]) -> Vec<String> {
+        items
+            .iter()
+            .filter_map(|i| match i {
+                DecodedItem::Frame { description, .. } => Some(description.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_notes_frames() {
+        let mut decoder = FrameDecoder::new();
+        let mut stream = vec![0x12, 0x34];
+        stream.extend([0xBE, 0x50, 0x00, 0x03, 0x00, 0x00, 0x01, 0xED]);
+        stream.extend([
+            0xBE, 0x60, 0x00, 0x06, 0x03, 0x01, 0x00, 0x90, 0x00, 0x00, 0x47,
+        ]);
+        stream.extend([0xBE, 0x65, 0x02, 0x01, 0x11, 0xC8]);
+        stream.extend([0xBE, 0x65, 0x02, 0x04, 0x00, 0xC8, 0x60, 0x16, 0x98]);
+        stream.extend([
+            0xBE, 0x61, 0x07, 0x0C, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x00, 0x0D, 0x00, 0x00, 0x80,
+            0x00, 0x00, 0x04,
+        ]);
+        let items = decoder.push(Direction::Unknown, &stream);
+        assert!(
+            matches!(&items[0], DecodedItem::Garbage { data, .. } if data == &vec![0x12, 0x34])
+        );
+        assert_eq!(
+            descriptions(&items),
+            vec![
+                "Sync seq=0x00 state=0 version=0x0100",
+                "ProgrammerInit seq=0x00 programmer version=0x0103 sector size=0x9000",
+                "FlashCommand seq=0x02 FLASH_CMD_GET_ID",
+                "FlashCommand seq=0x02 flash ID status=0x00 id=C8-60-16",
+                "EraseBurnStart seq=0x07 erase/burn start address=0x3C000000 length=0xD0000 block=0x8000",
+            ]
+        );
+    }
+    #[test]
+    fn test_decode_skips_burn_data() {
+        let mut decoder = FrameDecoder::new();
+        let mut stream = vec![
+            0xBE, 0x62, 0xC1, 0x0B, 0x00, 0x80, 0x00, 0x00, 0xAB, 0x77, 0x7F, 0xF4, 0x00, 0x00,
+            0x00, 0xFE,
+        ];
+        // Burn data full of sync bytes must not be mistaken for frames
+        stream.extend([0xBE; 0x8000]);
+        stream.extend([0xBE, 0x65, 0x02, 0x01, 0x11, 0xC8]);
+        let items = decoder.push(Direction::Tx, &stream[..100]);
+        assert_eq!(items.len(), 1);
+        let items = decoder.push(Direction::Tx, &stream[100..]);
+        assert_eq!(
+            items[0],
+            DecodedItem::RawData {
+                direction: Direction::Tx,
+                offset: 16,
+                length: 0x8000,
+                what: "flash burn data"
+            }
+        );
+        assert_eq!(
+            descriptions(&items),
+            vec!["FlashCommand seq=0x02 FLASH_CMD_GET_ID"]
+        );
+    }
+    #[test]
+    fn test_decode_flags_unknown_type() {
+        let mut decoder = FrameDecoder::new();
+        let items = decoder.push(Direction::Rx, &[0xBE, 0x77, 0x01, 0x01, 0x00, 0xC8]);
+        assert_eq!(
+            descriptions(&items),
+            vec!["UNKNOWN type 0x77 seq=0x01 data=[00]"]
+        );
+        assert!(decoder.finish().is_empty());
+    }
+}
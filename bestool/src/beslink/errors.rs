@@ -1,3 +1,7 @@
+use crate::beslink::{MessageTypes, Stage};
+use std::ops::Range;
+use std::path::PathBuf;
+
 #[derive(Debug)]
 pub enum BESLinkError {
     IOError {
@@ -13,9 +17,73 @@ pub enum BESLinkError {
         got: u8,
         wanted: u8,
     },
+    BadFrameLength {
+        failed_packet: Vec<u8>,
+        got: usize,
+        wanted: usize,
+    },
+    BadPayloadLength {
+        failed_packet: Vec<u8>,
+        got: usize,
+        wanted: usize,
+    },
+    UnexpectedMessageType {
+        failed_packet: Vec<u8>,
+        got: MessageTypes,
+        wanted: MessageTypes,
+    },
+    BadFlashAddress {
+        address: usize,
+        sector_size: usize,
+    },
+    BadFlashLength {
+        length: usize,
+        block_size: usize,
+    },
+    PastEndOfFlash {
+        address: usize,
+        length: usize,
+        flash_size: usize,
+    },
+    VerifyFailed {
+        mismatches: Vec<Range<usize>>,
+    },
+    BadFirmwareImage {
+        reason: String,
+    },
+    SegmentOutsideFlash {
+        address: usize,
+        length: usize,
+    },
+    UnalignedSegment {
+        address: usize,
+        sector_size: usize,
+    },
+    ReadUnstable {
+        address: usize,
+        reads: usize,
+    },
+    ShortRead {
+        address: usize,
+        got: usize,
+        wanted: usize,
+    },
+    BadProgrammerImage {
+        reason: &'static str,
+    },
+    UnknownChip {
+        rom_version: u16,
+    },
+    BadPort {
+        reason: String,
+    },
+    ProgrammerNotFound {
+        config_path: Option<PathBuf>,
+    },
     InvalidArgs,
-    // #[error("Communications timed out")]
-    // Timeout,
+    Timeout {
+        stage: Stage,
+    },
 }
 
 impl From<std::io::Error> for BESLinkError {
@@ -50,7 +118,129 @@ impl fmt::Display for BESLinkError {
                     "Bad response code: failed_packet={failed_packet:?}, got={got}, wanted={wanted}"
                 )
             }
+            BESLinkError::BadFrameLength {
+                failed_packet,
+                got,
+                wanted,
+            } => {
+                write!(
+                    f,
+                    "Bad frame length: failed_packet={failed_packet:?}, got={got}, wanted={wanted}"
+                )
+            }
+            BESLinkError::BadPayloadLength {
+                failed_packet,
+                got,
+                wanted,
+            } => {
+                write!(
+                    f,
+                    "Bad payload length: failed_packet={failed_packet:?}, got={got}, wanted={wanted}"
+                )
+            }
+            BESLinkError::UnexpectedMessageType {
+                failed_packet,
+                got,
+                wanted,
+            } => {
+                write!(
+                    f,
+                    "Unexpected message type: failed_packet={failed_packet:?}, got={got:?}, wanted={wanted:?}"
+                )
+            }
+            BESLinkError::BadFlashAddress {
+                address,
+                sector_size,
+            } => {
+                write!(
+                    f,
+                    "Flash address 0x{address:08X} is not on a 0x{sector_size:X} byte boundary"
+                )
+            }
+            BESLinkError::BadFlashLength { length, block_size } => {
+                write!(
+                    f,
+                    "Flash length 0x{length:X} is not a whole number of 0x{block_size:X} byte blocks"
+                )
+            }
+            BESLinkError::PastEndOfFlash {
+                address,
+                length,
+                flash_size,
+            } => {
+                write!(
+                    f,
+                    "0x{length:X} bytes at 0x{address:08X} run past the end of the 0x{flash_size:X} byte flash"
+                )
+            }
+            BESLinkError::VerifyFailed { mismatches } => {
+                write!(f, "Verify failed, {} ranges differ:", mismatches.len())?;
+                for range in mismatches.iter().take(16) {
+                    write!(f, " 0x{:08X}..0x{:08X}", range.start, range.end)?;
+                }
+                if mismatches.len() > 16 {
+                    write!(f, " ...")?;
+                }
+                Ok(())
+            }
+            BESLinkError::BadFirmwareImage { reason } => {
+                write!(f, "Not a usable firmware image, {reason}")
+            }
+            BESLinkError::SegmentOutsideFlash { address, length } => {
+                write!(
+                    f,
+                    "Image segment of 0x{length:X} bytes at 0x{address:08X} is not in flash; only flash can be written"
+                )
+            }
+            BESLinkError::UnalignedSegment {
+                address,
+                sector_size,
+            } => {
+                write!(
+                    f,
+                    "Image segment at 0x{address:08X} does not start on a 0x{sector_size:X} byte flash sector; burning it would erase the flash before it that the image does not cover"
+                )
+            }
+            BESLinkError::ShortRead {
+                address,
+                got,
+                wanted,
+            } => {
+                write!(
+                    f,
+                    "Read 0x{got:X} bytes of flash back from 0x{address:08X} when 0x{wanted:X} were asked for"
+                )
+            }
+            BESLinkError::ReadUnstable { address, reads } => {
+                write!(
+                    f,
+                    "No two consecutive reads of the chunk at 0x{address:08X} agreed in {reads} tries"
+                )
+            }
+            BESLinkError::BadProgrammerImage { reason } => {
+                write!(f, "Not a usable programmer image, {reason}")
+            }
+            BESLinkError::BadPort { reason } => write!(f, "{reason}"),
+            BESLinkError::UnknownChip { rom_version } => {
+                write!(
+                    f,
+                    "No chip profile for ROM version 0x{rom_version:04X}; only the BES2300 has one so far, give --chip to use it anyway"
+                )
+            }
+            BESLinkError::ProgrammerNotFound { config_path } => {
+                write!(
+                    f,
+                    "No programmer blob, this build does not embed one; give --programmer or set BESTOOL_PROGRAMMER"
+                )?;
+                match config_path {
+                    Some(path) => write!(f, ", or copy it to {}", path.display()),
+                    None => Ok(()),
+                }
+            }
             BESLinkError::InvalidArgs => write!(f, "Invalid arguments"),
+            BESLinkError::Timeout { stage } => {
+                write!(f, "Communications timed out during {stage}")
+            }
         }
     }
 }
@@ -0,0 +1,411 @@
+use crate::beslink::{BESLinkError, FLASH_BUFFER_SIZE};
+use std::fs;
+use std::path::Path;
+
+// Firmware images that say where their bytes go (Intel HEX, S-records and ELF), as opposed to raw
+// binaries which are written wherever they are told to be
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_PT_LOAD: usize = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImageSegment {
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+enum ImageFormat {
+    Raw,
+    IntelHex,
+    SRecord,
+    Elf,
+}
+
+fn image_format(path: &Path) -> ImageFormat {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("hex" | "ihex") => ImageFormat::IntelHex,
+        Some("srec" | "s19" | "s28" | "s37") => ImageFormat::SRecord,
+        Some("elf" | "axf") => ImageFormat::Elf,
+        _ => ImageFormat::Raw,
+    }
+}
+
+// Raw binaries carry no addresses of their own, so they come back as None
+pub fn load_image_segments(path: &Path) -> Result<Option<Vec<ImageSegment>>, BESLinkError> {
+    let segments = match image_format(path) {
+        ImageFormat::Raw => return Ok(None),
+        ImageFormat::IntelHex => parse_intel_hex(&fs::read_to_string(path)?)?,
+        ImageFormat::SRecord => parse_srec(&fs::read_to_string(path)?)?,
+        ImageFormat::Elf => parse_elf(&fs::read(path)?)?,
+    };
+    if segments.is_empty() {
+        return Err(bad_image("it holds no data".to_owned()));
+    }
+    Ok(Some(segments))
+}
+
+// Packs the segments into regions that can each be burnt in one go. Burns are padded out to whole flash
+// buffers, so a segment starting inside the previous region's last buffer joins it with the gap left blank.
+// Anything else starts a region of its own, which has to be on a sector boundary
+pub fn flash_regions(
+    mut segments: Vec<ImageSegment>,
+    flash_base: usize,
+    flash_size: Option<usize>,
+    sector_size: usize,
+) -> Result<Vec<ImageSegment>, BESLinkError> {
+    segments.sort_by_key(|segment| segment.address);
+    let sector_size = sector_size.max(1);
+    let flash_end = flash_size.map(|size| flash_base + size);
+    let mut regions: Vec<ImageSegment> = vec![];
+    for segment in segments {
+        let end = segment.address + segment.data.len();
+        if segment.address < flash_base || flash_end.is_some_and(|flash_end| end > flash_end) {
+            return Err(BESLinkError::SegmentOutsideFlash {
+                address: segment.address,
+                length: segment.data.len(),
+            });
+        }
+        if let Some(region) = regions.last_mut() {
+            if segment.address < region.address + region.data.len() {
+                return Err(bad_image(format!(
+                    "two segments overlap at 0x{:08X}",
+                    segment.address
+                )));
+            }
+            let padded_end = region.address + region.data.len().next_multiple_of(FLASH_BUFFER_SIZE);
+            if segment.address <= padded_end {
+                region.data.resize(segment.address - region.address, 0xFF);
+                region.data.extend(segment.data);
+                continue;
+            }
+        }
+        // Rounding the start down would blank whatever is in flash before the segment, such as the end of
+        // the bootloader, so it is refused instead
+        if !(segment.address - flash_base).is_multiple_of(sector_size) {
+            return Err(BESLinkError::UnalignedSegment {
+                address: segment.address,
+                sector_size,
+            });
+        }
+        regions.push(segment);
+    }
+    Ok(regions)
+}
+
+fn bad_image(reason: String) -> BESLinkError {
+    BESLinkError::BadFirmwareImage { reason }
+}
+
+// Records usually follow on from each other, so they are gathered into as few segments as possible
+fn push_data(segments: &mut Vec<ImageSegment>, address: usize, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    match segments.last_mut() {
+        Some(last) if last.address + last.data.len() == address => last.data.extend(data),
+        _ => segments.push(ImageSegment {
+            address,
+            data: data.to_vec(),
+        }),
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// :LLAAAATT<data>CC, where the checksum makes every byte of the record sum to zero
+fn parse_intel_hex(text: &str) -> Result<Vec<ImageSegment>, BESLinkError> {
+    let mut segments = vec![];
+    let mut base = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line_number = i + 1;
+        let record = line
+            .strip_prefix(':')
+            .and_then(decode_hex)
+            .filter(|record| record.len() >= 5 && record.len() == record[0] as usize + 5)
+            .ok_or_else(|| bad_image(format!("line {line_number} is not an Intel HEX record")))?;
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(bad_image(format!("line {line_number} has a bad checksum")));
+        }
+        let offset = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let data = &record[4..record.len() - 1];
+        match (record[3], data) {
+            (0x00, _) => push_data(&mut segments, base + offset, data),
+            (0x01, _) => break,
+            (0x02, [hi, lo]) => base = (u16::from_be_bytes([*hi, *lo]) as usize) << 4,
+            (0x04, [hi, lo]) => base = (u16::from_be_bytes([*hi, *lo]) as usize) << 16,
+            (0x02 | 0x04, _) => {
+                return Err(bad_image(format!(
+                    "line {line_number} is an extended address record without a 2 byte address"
+                )))
+            }
+            // Start addresses mean nothing to the flash
+            (0x03 | 0x05, _) => {}
+            (record_type, _) => {
+                return Err(bad_image(format!(
+                    "line {line_number} has an unusable record type {record_type:02X}"
+                )))
+            }
+        }
+    }
+    Ok(segments)
+}
+
+// S<type><count><address><data><checksum>, where the count covers everything after itself and the
+// checksum is the ones' complement of the sum of the count, address and data
+fn parse_srec(text: &str) -> Result<Vec<ImageSegment>, BESLinkError> {
+    let mut segments = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line_number = i + 1;
+        let (record_type, record) = line
+            .strip_prefix('S')
+            .and_then(|rest| Some((rest.get(..1)?, decode_hex(rest.get(1..)?)?)))
+            .filter(|(_, record)| record.len() >= 2 && record.len() == record[0] as usize + 1)
+            .ok_or_else(|| bad_image(format!("line {line_number} is not an S-record")))?;
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+            return Err(bad_image(format!("line {line_number} has a bad checksum")));
+        }
+        let address_size = match record_type {
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            // Header, record counts and start addresses
+            "0" | "5" | "6" | "7" | "8" | "9" => continue,
+            _ => {
+                return Err(bad_image(format!(
+                    "line {line_number} has an unusable record type S{record_type}"
+                )))
+            }
+        };
+        if record.len() < address_size + 2 {
+            return Err(bad_image(format!("line {line_number} is too short")));
+        }
+        let address = record[1..=address_size]
+            .iter()
+            .fold(0, |address, b| (address << 8) | *b as usize);
+        push_data(
+            &mut segments,
+            address,
+            &record[address_size + 1..record.len() - 1],
+        );
+    }
+    Ok(segments)
+}
+
+// Only 32 bit little endian files, which is what the Cortex-M toolchains produce
+fn parse_elf(bytes: &[u8]) -> Result<Vec<ImageSegment>, BESLinkError> {
+    if !bytes.starts_with(&ELF_MAGIC) || bytes.len() < 0x34 {
+        return Err(bad_image("not an ELF file".to_owned()));
+    }
+    if bytes[4] != ELF_CLASS_32 || bytes[5] != ELF_DATA_LITTLE_ENDIAN {
+        return Err(bad_image(
+            "only 32 bit little endian ELF files are supported".to_owned(),
+        ));
+    }
+    let field = |offset: usize, size: usize| {
+        bytes
+            .get(offset..offset + size)
+            .map(|b| {
+                b.iter()
+                    .rev()
+                    .fold(0, |value, b| (value << 8) | *b as usize)
+            })
+            .ok_or_else(|| bad_image("a program header runs past the end of the file".to_owned()))
+    };
+    let header_offset = field(0x1C, 4)?;
+    let header_size = field(0x2A, 2)?;
+    let header_count = field(0x2C, 2)?;
+    let mut segments = vec![];
+    for header in (0..header_count).map(|i| header_offset + i * header_size) {
+        let file_size = field(header + 0x10, 4)?;
+        if field(header, 4)? != ELF_PT_LOAD || file_size == 0 {
+            continue;
+        }
+        let offset = field(header + 0x04, 4)?;
+        // The physical address is where the bytes are stored; initialised data is copied from
+        // there to its (RAM) virtual address at boot
+        let address = field(header + 0x0C, 4)?;
+        let data = bytes
+            .get(offset..offset + file_size)
+            .ok_or_else(|| bad_image("a segment runs past the end of the file".to_owned()))?;
+        segments.push(ImageSegment {
+            address,
+            data: data.to_vec(),
+        });
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::firmware_image::{
+        flash_regions, parse_elf, parse_intel_hex, parse_srec, ImageSegment,
+    };
+    use crate::beslink::{BESLinkError, BES_FLASH_BASE, FLASH_BUFFER_SIZE};
+
+    fn segment(address: usize, data: &[u8]) -> ImageSegment {
+        ImageSegment {
+            address,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_intel_hex() {
+        let hex = ":020000043C00BE\n\
+                   :0400000001020304F2\n\
+                   :020004000506EF\n\
+                   :02100000AABB89\n\
+                   :040000053C000101B9\n\
+                   :00000001FF\n";
+        assert_eq!(
+            parse_intel_hex(hex).unwrap(),
+            vec![
+                segment(0x3C00_0000, &[1, 2, 3, 4, 5, 6]),
+                segment(0x3C00_1000, &[0xAA, 0xBB]),
+            ]
+        );
+        assert!(matches!(
+            parse_intel_hex(":0400000001020304F3\n"),
+            Err(BESLinkError::BadFirmwareImage { .. })
+        ));
+        // A known record type with the wrong length is malformed, not unusable
+        assert!(matches!(
+            parse_intel_hex(":030000043C0000BD\n"),
+            Err(BESLinkError::BadFirmwareImage { reason }) if reason.contains("extended address record")
+        ));
+    }
+    #[test]
+    fn test_parse_srec() {
+        let srec = "S00600004844521B\n\
+                    S3093C00000001020304B0\n\
+                    S3073C0000040506AD\n\
+                    S7053C000000BE\n";
+        assert_eq!(
+            parse_srec(srec).unwrap(),
+            vec![segment(0x3C00_0000, &[1, 2, 3, 4, 5, 6])]
+        );
+        assert!(parse_srec("S3093C00000001020304B1\n").is_err());
+    }
+    #[test]
+    fn test_parse_elf_uses_load_addresses() {
+        // Header, then two program headers: code in flash, and data that runs from RAM but is stored in flash
+        let mut elf = vec![0u8; 0x34];
+        elf[..6].copy_from_slice(&[0x7F, b'E', b'L', b'F', 1, 1]);
+        elf[0x1C..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        elf[0x2A..0x2C].copy_from_slice(&0x20u16.to_le_bytes());
+        elf[0x2C..0x2E].copy_from_slice(&3u16.to_le_bytes());
+        let data_offset = 0x34 + 3 * 0x20;
+        for (kind, offset, vaddr, paddr, size) in [
+            (1u32, data_offset, 0x3C00_0000u32, 0x3C00_0000u32, 4u32),
+            (1, data_offset + 4, 0x2000_0000, 0x3C00_8000, 2),
+            // .bss has nothing in the file
+            (1, data_offset + 6, 0x2000_0100, 0x2000_0100, 0),
+        ] {
+            for value in [kind, offset as u32, vaddr, paddr, size, size, 0, 0] {
+                elf.extend(value.to_le_bytes());
+            }
+        }
+        elf.extend([1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            parse_elf(&elf).unwrap(),
+            vec![
+                segment(0x3C00_0000, &[1, 2, 3, 4]),
+                segment(0x3C00_8000, &[5, 6]),
+            ]
+        );
+    }
+    #[test]
+    fn test_flash_regions() {
+        let segments = vec![
+            segment(BES_FLASH_BASE + 0x10_0000, &[7; 0x10]),
+            segment(BES_FLASH_BASE + 0x1010, &[2; 0x10]),
+            segment(BES_FLASH_BASE + 0x1000, &[1; 0x10]),
+        ];
+        let regions = flash_regions(segments, BES_FLASH_BASE, None, 0x1000).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].address, BES_FLASH_BASE + 0x1000);
+        assert_eq!(regions[0].data, [[1; 0x10], [2; 0x10]].concat());
+        assert_eq!(regions[1].address, BES_FLASH_BASE + 0x10_0000);
+
+        // A segment within the last buffer of a region joins it, with the gap left blank
+        let segments = vec![
+            segment(BES_FLASH_BASE + 0x1000, &[1]),
+            segment(BES_FLASH_BASE + 0x1000 + FLASH_BUFFER_SIZE, &[2]),
+        ];
+        let regions = flash_regions(segments, BES_FLASH_BASE, None, 0x1000).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].address, BES_FLASH_BASE + 0x1000);
+        assert_eq!(regions[0].data.len(), FLASH_BUFFER_SIZE + 1);
+        assert!(regions[0].data[1..FLASH_BUFFER_SIZE]
+            .iter()
+            .all(|b| *b == 0xFF));
+
+        assert!(matches!(
+            flash_regions(
+                vec![segment(0x2000_0000, &[1])],
+                BES_FLASH_BASE,
+                None,
+                0x1000
+            ),
+            Err(BESLinkError::SegmentOutsideFlash { .. })
+        ));
+        // Running off the end of a 4MiB part
+        assert!(matches!(
+            flash_regions(
+                vec![segment(BES_FLASH_BASE + 0x3F_FFF0, &[1; 0x20])],
+                BES_FLASH_BASE,
+                Some(0x40_0000),
+                0x1000
+            ),
+            Err(BESLinkError::SegmentOutsideFlash {
+                address: 0x3C3F_FFF0,
+                length: 0x20
+            })
+        ));
+    }
+    #[test]
+    fn test_flash_regions_refuses_unaligned_segments() {
+        // Data at 0x3C000800, half way into the first sector
+        let hex = ":020000043C00BE\n\
+                   :0408000001020304EA\n\
+                   :00000001FF\n";
+        let segments = parse_intel_hex(hex).unwrap();
+        assert!(matches!(
+            flash_regions(segments, BES_FLASH_BASE, Some(0x40_0000), 0x1000),
+            Err(BESLinkError::UnalignedSegment {
+                address: 0x3C00_0800,
+                sector_size: 0x1000
+            })
+        ));
+        let srec = "S3093C00080001020304A8\n";
+        assert!(matches!(
+            flash_regions(parse_srec(srec).unwrap(), BES_FLASH_BASE, None, 0x1000),
+            Err(BESLinkError::UnalignedSegment {
+                address: 0x3C00_0800,
+                ..
+            })
+        ));
+    }
+}
@@ -1,41 +1,131 @@
 use crate::beslink::{
-    load_programmer_runtime_binary_blob, query_memory_info, send_message,
-    start_programmer_runtime_binary_blob, sync, BESLinkError, BesMessage, MessageTypes, BES_SYNC,
+    chip_baud_rate, load_programmer_runtime_binary_blob, negotiate_baud_rate, query_memory_info,
+    resolve_chip, run_reset_sequence, send_message, start_programmer_runtime_binary_blob, sync,
+    BESLinkError, BesMessage, BesTransport, ChipProfile, Deadline, FlashId, FlashUniqueId,
+    MessageTypes, ProgrammerImage, ProgrammerInfo, ResetSequences, ResetStep, Stage, SyncInfo,
+    Timeouts, SYNC_STATE_CONFIRMED,
 };
-use serialport::SerialPort;
 use std::time::Duration;
+
+const RESET_RETRY_INTERVAL: Duration = Duration::from_secs(2);
 use tracing::{info, warn};
 
+// Everything the device tells us on the way to having the programmer running
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeviceInfo {
+    pub chip: &'static ChipProfile,
+    pub rom_version: u16,
+    pub programmer: ProgrammerInfo,
+    pub flash_id: FlashId,
+    pub unique_id: FlashUniqueId,
+}
+
+// What the command line says about the device, as opposed to what it reports about itself
+#[derive(Debug, Default, Clone)]
+pub struct ProgrammingOptions {
+    // Used whatever the ROM version; otherwise the chip is recognised from it
+    pub chip: Option<&'static ChipProfile>,
+    // Without these the board has to be reset into its ROM by hand
+    pub reset: Option<ResetSequences>,
+    // The programmer is moved to this rate once it is running, if it will go
+    pub baud_rate: Option<u32>,
+    // Loaded instead of the chip's own programmer
+    pub programmer: Option<ProgrammerImage>,
+}
+
+impl ProgrammingOptions {
+    // What the ROM is synced at, before the chip has said what it is
+    pub fn programming_baud_rate(&self) -> u32 {
+        chip_baud_rate(self.chip)
+    }
+}
+
 pub fn helper_sync_and_load_programmer(
-    serial_port: &mut Box<dyn SerialPort>,
-) -> Result<(), BESLinkError> {
-    sync_with_bootloader(serial_port)?;
-    info!("In bootloader");
-    load_programmer_runtime_binary_blob(serial_port)?;
+    transport: &mut dyn BesTransport,
+    options: &ProgrammingOptions,
+    timeouts: &Timeouts,
+) -> Result<DeviceInfo, BESLinkError> {
+    // A previous session may have left the port at a negotiated rate, but the ROM always starts at the default
+    transport.set_baud_rate(options.programming_baud_rate())?;
+    let rom_version = sync_with_bootloader(transport, options.reset.as_ref(), timeouts)?;
+    let chip = resolve_chip(rom_version, options.chip)?;
+    info!(
+        "In bootloader, ROM version 0x{:04X}, {}",
+        rom_version, chip.name
+    );
+    load_programmer_runtime_binary_blob(transport, chip, options.programmer.as_ref(), timeouts)?;
     info!("Loaded programmer blob");
-    start_programmer_runtime_binary_blob(serial_port)?;
-    info!("Started programmer blob");
-    query_memory_info(serial_port)?;
+    let programmer_info = start_programmer_runtime_binary_blob(transport, timeouts)?;
+    info!(
+        "Started programmer blob, version 0x{:04X} with sector size 0x{:X}",
+        programmer_info.version, programmer_info.sector_size
+    );
+    if programmer_info.version != chip.programmer_version {
+        warn!(
+            "Programmer version 0x{:04X} is not the 0x{:04X} expected on a {}",
+            programmer_info.version, chip.programmer_version, chip.name
+        );
+    }
+    if let Some(baud_rate) = options.baud_rate
+        && baud_rate != chip.baud_rate
+    {
+        negotiate_baud_rate(transport, chip, baud_rate, timeouts)?;
+    }
+    let (flash_id, unique_id) = query_memory_info(transport, timeouts)?;
     info!("Got Memory info Done; so programmer blob is working");
-    Ok(())
+    Ok(DeviceInfo {
+        chip,
+        rom_version,
+        programmer: programmer_info,
+        flash_id,
+        unique_id,
+    })
 }
 fn get_stay_in_programmer_message() -> BesMessage {
-    BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::Sync,
-        payload: vec![0x00, 0x01, 0x01],
-        checksum: 0xEF,
+    BesMessage::new(MessageTypes::Sync, 0x00, vec![0x01])
+}
+// The ROM only listens for a moment after reset, so keep resetting until it is caught
+fn reset_until_advertised(
+    transport: &mut dyn BesTransport,
+    into_rom: &[ResetStep],
+    deadline: &Deadline,
+) -> Result<BesMessage, BESLinkError> {
+    loop {
+        run_reset_sequence(transport, into_rom)?;
+        send_message(transport, get_stay_in_programmer_message())?;
+        match sync(
+            transport,
+            MessageTypes::Sync,
+            &deadline.at_most(RESET_RETRY_INTERVAL),
+        ) {
+            Err(BESLinkError::Timeout { .. }) => {
+                deadline.check()?;
+                warn!("No sync advertisement after reset, trying again");
+            }
+            result => return result,
+        }
     }
 }
-fn sync_with_bootloader(serial_port: &mut Box<dyn SerialPort>) -> Result<(), BESLinkError> {
+fn sync_with_bootloader(
+    transport: &mut dyn BesTransport,
+    reset: Option<&ResetSequences>,
+    timeouts: &Timeouts,
+) -> Result<u16, BESLinkError> {
     // Gain sync
     info!("Syncing into bootloader");
+    // One deadline for the whole handshake, otherwise a device stuck sending bad sync responses never times out
+    let deadline = timeouts.deadline(Stage::Sync);
 
-    match send_message(serial_port, get_stay_in_programmer_message()) {
-        Ok(_) => {}
-        Err(e) => return Err(BESLinkError::from(e)),
+    let sync_message = match reset {
+        Some(reset) => reset_until_advertised(transport, &reset.into_rom, &deadline)?,
+        None => {
+            match send_message(transport, get_stay_in_programmer_message()) {
+                Ok(_) => {}
+                Err(e) => return Err(BESLinkError::from(e)),
+            };
+            sync(transport, MessageTypes::Sync, &deadline)?
+        }
     };
-    let sync_message = sync(serial_port, MessageTypes::Sync)?;
     info!("Received sync advertisement {:X?}", sync_message.to_vec());
     loop {
         std::thread::sleep(Duration::from_millis(2));
@@ -43,17 +133,18 @@ fn sync_with_bootloader(serial_port: &mut Box<dyn SerialPort>) -> Result<(), BES
         info!("Saw boot sync, sending ack");
         // Send message to stay in bootloader
 
-        match send_message(serial_port, get_stay_in_programmer_message()) {
+        match send_message(transport, get_stay_in_programmer_message()) {
             Ok(_) => {}
             Err(e) => return Err(BESLinkError::from(e)),
         };
         info!("Sent sync message");
-        let response = sync(serial_port, MessageTypes::Sync)?;
+        let response = sync(transport, MessageTypes::Sync, &deadline)?;
         info!("Sync response: {:X?}", response.to_vec());
-        if response.payload[2] == 0x02 && response.payload[3] == 0x00 {
-            return Ok(());
-        } else {
-            warn!("Received bad sync response {:X?}", response.to_vec());
+        match SyncInfo::from_message(&response) {
+            Ok(sync_info) if sync_info.state == SYNC_STATE_CONFIRMED => {
+                return Ok(sync_info.version);
+            }
+            _ => warn!("Received bad sync response {:X?}", response.to_vec()),
         }
     }
 }
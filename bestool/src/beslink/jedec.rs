@@ -0,0 +1,158 @@
+use crate::beslink::FlashId;
+
+// JEDEC (JEP106) manufacturer codes of the SPI NOR vendors found on BES boards
+pub fn manufacturer_name(id: u8) -> Option<&'static str> {
+    let name = match id {
+        0x01 => "Spansion/Cypress",
+        0x0B => "XTX",
+        0x1C => "EON",
+        0x1F => "Adesto",
+        0x20 => "XMC/Micron",
+        0x5E => "Zbit",
+        0x68 => "Boya",
+        0x85 => "Puya",
+        0x9D => "ISSI",
+        0xA1 => "Fudan",
+        0xBF => "SST/Microchip",
+        0xC2 => "Macronix",
+        0xC8 => "GigaDevice",
+        0xEF => "Winbond",
+        _ => return None,
+    };
+    Some(name)
+}
+
+pub struct FlashPart {
+    pub id: [u8; 3],
+    pub vendor: &'static str,
+    pub name: &'static str,
+    pub size: usize,
+    // Smallest erase the part can do, which is what burns have to line up with
+    pub sector_size: usize,
+}
+
+const MIB: usize = 1024 * 1024;
+// Every part in the table has the usual 4KiB sector erase
+const SECTOR_4K: usize = 0x1000;
+// Parts we know nothing about are held to the 64KiB block erase all SPI NOR parts have,
+// so a burn never starts part way through a sector
+const UNKNOWN_PART_SECTOR_SIZE: usize = 0x1_0000;
+
+// Common SPI NOR parts, keyed on the manufacturer, memory type and capacity bytes of the ID.
+// The 1.8V parts are what BES boards usually carry
+const FLASH_PARTS: &[FlashPart] = &[
+    part([0xC8, 0x40, 0x14], "GigaDevice", "GD25Q80", MIB),
+    part([0xC8, 0x40, 0x15], "GigaDevice", "GD25Q16", 2 * MIB),
+    part([0xC8, 0x40, 0x16], "GigaDevice", "GD25Q32", 4 * MIB),
+    part([0xC8, 0x40, 0x17], "GigaDevice", "GD25Q64", 8 * MIB),
+    part([0xC8, 0x40, 0x18], "GigaDevice", "GD25Q128", 16 * MIB),
+    part([0xC8, 0x60, 0x15], "GigaDevice", "GD25LQ16", 2 * MIB),
+    part([0xC8, 0x60, 0x16], "GigaDevice", "GD25LQ32", 4 * MIB),
+    part([0xC8, 0x60, 0x17], "GigaDevice", "GD25LQ64", 8 * MIB),
+    part([0xC8, 0x60, 0x18], "GigaDevice", "GD25LQ128", 16 * MIB),
+    part([0xEF, 0x40, 0x14], "Winbond", "W25Q80", MIB),
+    part([0xEF, 0x40, 0x15], "Winbond", "W25Q16", 2 * MIB),
+    part([0xEF, 0x40, 0x16], "Winbond", "W25Q32", 4 * MIB),
+    part([0xEF, 0x40, 0x17], "Winbond", "W25Q64", 8 * MIB),
+    part([0xEF, 0x40, 0x18], "Winbond", "W25Q128", 16 * MIB),
+    part([0xEF, 0x60, 0x15], "Winbond", "W25Q16JW", 2 * MIB),
+    part([0xEF, 0x60, 0x16], "Winbond", "W25Q32JW", 4 * MIB),
+    part([0xEF, 0x60, 0x17], "Winbond", "W25Q64JW", 8 * MIB),
+    part([0xEF, 0x60, 0x18], "Winbond", "W25Q128JW", 16 * MIB),
+    part([0xC2, 0x20, 0x15], "Macronix", "MX25L1606E", 2 * MIB),
+    part([0xC2, 0x20, 0x16], "Macronix", "MX25L3206E", 4 * MIB),
+    part([0xC2, 0x20, 0x17], "Macronix", "MX25L6406E", 8 * MIB),
+    part([0xC2, 0x20, 0x18], "Macronix", "MX25L12835F", 16 * MIB),
+    // Macronix 1.8V parts do not use the power of two capacity code
+    part([0xC2, 0x25, 0x35], "Macronix", "MX25U1635F", 2 * MIB),
+    part([0xC2, 0x25, 0x36], "Macronix", "MX25U3235F", 4 * MIB),
+    part([0xC2, 0x25, 0x37], "Macronix", "MX25U6435F", 8 * MIB),
+    part([0x85, 0x60, 0x15], "Puya", "P25Q16H", 2 * MIB),
+    part([0x85, 0x60, 0x16], "Puya", "P25Q32H", 4 * MIB),
+    part([0x85, 0x60, 0x17], "Puya", "P25Q64H", 8 * MIB),
+    part([0x20, 0x40, 0x16], "XMC", "XM25QH32B", 4 * MIB),
+    part([0x20, 0x40, 0x17], "XMC", "XM25QH64A", 8 * MIB),
+    part([0x5E, 0x40, 0x16], "Zbit", "ZB25VQ32", 4 * MIB),
+    part([0x5E, 0x40, 0x17], "Zbit", "ZB25VQ64", 8 * MIB),
+    part([0x68, 0x40, 0x16], "Boya", "BY25Q32", 4 * MIB),
+    part([0x68, 0x40, 0x17], "Boya", "BY25Q64", 8 * MIB),
+    part([0x0B, 0x40, 0x16], "XTX", "XT25F32B", 4 * MIB),
+    part([0x0B, 0x40, 0x17], "XTX", "XT25F64B", 8 * MIB),
+];
+
+const fn part(id: [u8; 3], vendor: &'static str, name: &'static str, size: usize) -> FlashPart {
+    FlashPart {
+        id,
+        vendor,
+        name,
+        size,
+        sector_size: SECTOR_4K,
+    }
+}
+
+pub fn lookup_flash_part(flash_id: &FlashId) -> Option<&'static FlashPart> {
+    let id = [
+        flash_id.manufacturer,
+        flash_id.memory_type,
+        flash_id.capacity,
+    ];
+    FLASH_PARTS.iter().find(|part| part.id == id)
+}
+
+// Known parts first, then the capacity code for anything not in the table
+pub fn detected_flash_size(flash_id: &FlashId) -> Option<usize> {
+    lookup_flash_part(flash_id)
+        .map(|part| part.size)
+        .or(flash_id.size())
+}
+
+// The programmer's own sector size (0x9000 on the BES2300) has never been seen to match any flash
+// part, so alignment goes by the part itself
+pub fn flash_sector_size(flash_id: &FlashId) -> usize {
+    lookup_flash_part(flash_id).map_or(UNKNOWN_PART_SECTOR_SIZE, |part| part.sector_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::jedec::{
+        detected_flash_size, flash_sector_size, lookup_flash_part, manufacturer_name,
+    };
+    use crate::beslink::FlashId;
+
+    #[test]
+    fn test_manufacturer_name() {
+        assert_eq!(manufacturer_name(0xC8), Some("GigaDevice"));
+        assert_eq!(manufacturer_name(0x00), None);
+    }
+    #[test]
+    fn test_lookup_flash_part() {
+        let flash_id = FlashId {
+            manufacturer: 0xC8,
+            memory_type: 0x60,
+            capacity: 0x16,
+        };
+        let part = lookup_flash_part(&flash_id).unwrap();
+        assert_eq!((part.vendor, part.name), ("GigaDevice", "GD25LQ32"));
+        assert_eq!(detected_flash_size(&flash_id), Some(4 * 1024 * 1024));
+        assert_eq!(flash_sector_size(&flash_id), 0x1000);
+
+        // Only the table knows this one's size
+        let flash_id = FlashId {
+            manufacturer: 0xC2,
+            memory_type: 0x25,
+            capacity: 0x36,
+        };
+        assert_eq!(flash_id.size(), None);
+        assert_eq!(detected_flash_size(&flash_id), Some(4 * 1024 * 1024));
+
+        // Unknown parts fall back to the capacity code
+        let flash_id = FlashId {
+            manufacturer: 0x12,
+            memory_type: 0x34,
+            capacity: 0x15,
+        };
+        assert!(lookup_flash_part(&flash_id).is_none());
+        assert_eq!(detected_flash_size(&flash_id), Some(2 * 1024 * 1024));
+        assert_eq!(flash_sector_size(&flash_id), 0x1_0000);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::beslink::{BesTransport, BES_PROGRAMMING_BAUDRATE};
+use std::collections::VecDeque;
+use std::io::ErrorKind::TimedOut;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// In memory transport for tests; bytes queued with push_incoming are handed to the reader,
+// and everything written is kept so it can be checked afterwards
+pub struct MemoryTransport {
+    incoming: VecDeque<u8>,
+    written: Vec<u8>,
+    timeout: Duration,
+    baud_rate: u32,
+    // Behave like a socket whose far end owns the line speed
+    pub fixed_baud_rate: bool,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport {
+            incoming: VecDeque::new(),
+            written: vec![],
+            timeout: Duration::from_millis(1000),
+            baud_rate: BES_PROGRAMMING_BAUDRATE,
+            fixed_baud_rate: false,
+        }
+    }
+    pub fn push_incoming(&mut self, data: &[u8]) {
+        self.incoming.extend(data);
+    }
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.incoming.is_empty() {
+            // Nothing will ever arrive, so behave like a uart whose read timeout expired
+            return Err(std::io::Error::from(TimedOut));
+        }
+        let n = buf.len().min(self.incoming.len());
+        for (dest, src) in buf.iter_mut().zip(self.incoming.drain(..n)) {
+            *dest = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BesTransport for MemoryTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        !self.fixed_baud_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::memory_transport::MemoryTransport;
+    use crate::beslink::message::read_message;
+    use crate::beslink::{
+        send_message, sync, BESLinkError, BesMessage, MessageTypes, Stage, Timeouts,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_send_message_writes_frame() {
+        let mut transport = MemoryTransport::new();
+        let msg = BesMessage::new(MessageTypes::ProgrammerStart, 0x01, vec![]);
+        send_message(&mut transport, msg).unwrap();
+        assert_eq!(transport.written(), &[0xBE, 0x55, 0x01, 0x00, 0xEB]);
+    }
+    #[test]
+    fn test_read_message_skips_leading_noise() {
+        let mut transport = MemoryTransport::new();
+        transport.push_incoming(&[0x00, 0x12, 0xBE, 0x50, 0x00, 0x03, 0x00, 0x00, 0x01, 0xED]);
+        let msg = read_message(
+            &mut transport,
+            &Timeouts::default().deadline(Stage::Command),
+        )
+        .unwrap();
+        assert_eq!(msg.type1(), MessageTypes::Sync);
+        assert_eq!(msg.payload(), [0x00, 0x00, 0x01]);
+    }
+    #[test]
+    fn test_sync_ignores_other_packets() {
+        let mut transport = MemoryTransport::new();
+        transport.push_incoming(&[0xBE, 0x50, 0x00, 0x03, 0x00, 0x00, 0x01, 0xED]);
+        transport.push_incoming(&[0xBE, 0x65, 0x02, 0x01, 0x11, 0xC8]);
+        let msg = sync(
+            &mut transport,
+            MessageTypes::FlashCommand,
+            &Timeouts::default().deadline(Stage::Command),
+        )
+        .unwrap();
+        assert_eq!(msg.to_vec(), vec![0xBE, 0x65, 0x02, 0x01, 0x11, 0xC8]);
+    }
+    #[test]
+    fn test_silent_device_times_out_with_stage() {
+        let mut transport = MemoryTransport::new();
+        let timeouts = Timeouts {
+            erase: Duration::from_millis(20),
+            ..Timeouts::default()
+        };
+        // Only part of a frame ever arrives
+        transport.push_incoming(&[0xBE, 0x53]);
+        let err = sync(
+            &mut transport,
+            MessageTypes::EraseBurnStart,
+            &timeouts.deadline(Stage::Erase),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            BESLinkError::Timeout {
+                stage: Stage::Erase
+            }
+        ));
+        assert_eq!(err.to_string(), "Communications timed out during erase");
+    }
+}
@@ -1,98 +1,149 @@
-use crate::beslink::{BESLinkError, BES_SYNC, FLASH_BUFFER_SIZE};
-use serialport::SerialPort;
-use std::convert::TryFrom;
+use crate::beslink::{BESLinkError, BesTransport, Deadline, BES_SYNC, FLASH_BUFFER_SIZE};
 use std::io::ErrorKind::TimedOut;
-use std::io::{Read, Write};
 
 use tracing::{debug, error, info, warn};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MessageTypes {
-    DeviceCommand = 0x00, // General commands to the device
-    FlashRead = 0x03,     // Debugging message that lets you dump from address space
-    Sync = 0x50,          // Seems to be used at boot for locking with ROM
-    StartProgrammer = 0x53,
-    ProgrammerRunning = 0x54,
-    ProgrammerStart = 0x55,
-    ProgrammerInit = 0x60,
-    EraseBurnStart = 0x61,
-    FlashBurnData = 0x62,
-    FlashCommand = 0x65, // Suspect used to push extra commands to flash controller/chip/die
-    UnknownORInfo = 0x66, // Unknown at this point in time, but references "OR Info"; suspect NOR flash info
+    DeviceCommand,     // 0x00 General commands to the device
+    FlashRead,         // 0x03 Debugging message that lets you dump from address space
+    Sync,              // 0x50 Seems to be used at boot for locking with ROM
+    StartProgrammer,   // 0x53
+    ProgrammerRunning, // 0x54
+    ProgrammerStart,   // 0x55
+    ProgrammerInit,    // 0x60
+    EraseBurnStart,    // 0x61
+    FlashBurnData,     // 0x62
+    FlashCommand,      // 0x65 Suspect used to push extra commands to flash controller/chip/die
+    UnknownORInfo, // 0x66 Unknown at this point in time, but references "OR Info"; suspect NOR flash info
+    Unknown(u8),   // Anything else the device sent, kept as the raw type byte
 }
-impl TryFrom<u8> for MessageTypes {
-    type Error = ();
-
-    fn try_from(v: u8) -> Result<Self, Self::Error> {
+impl From<u8> for MessageTypes {
+    fn from(v: u8) -> Self {
+        match v {
+            0x00 => MessageTypes::DeviceCommand,
+            0x03 => MessageTypes::FlashRead,
+            0x50 => MessageTypes::Sync,
+            0x53 => MessageTypes::StartProgrammer,
+            0x54 => MessageTypes::ProgrammerRunning,
+            0x55 => MessageTypes::ProgrammerStart,
+            0x60 => MessageTypes::ProgrammerInit,
+            0x61 => MessageTypes::EraseBurnStart,
+            0x62 => MessageTypes::FlashBurnData,
+            0x65 => MessageTypes::FlashCommand,
+            0x66 => MessageTypes::UnknownORInfo,
+            _ => MessageTypes::Unknown(v),
+        }
+    }
+}
+impl From<MessageTypes> for u8 {
+    fn from(v: MessageTypes) -> Self {
         match v {
-            x if x == MessageTypes::Sync as u8 => Ok(MessageTypes::Sync),
-            x if x == MessageTypes::StartProgrammer as u8 => Ok(MessageTypes::StartProgrammer),
-            x if x == MessageTypes::ProgrammerRunning as u8 => Ok(MessageTypes::ProgrammerRunning),
-            x if x == MessageTypes::ProgrammerInit as u8 => Ok(MessageTypes::ProgrammerInit),
-            x if x == MessageTypes::ProgrammerStart as u8 => Ok(MessageTypes::ProgrammerStart),
-            x if x == MessageTypes::FlashCommand as u8 => Ok(MessageTypes::FlashCommand),
-            x if x == MessageTypes::EraseBurnStart as u8 => Ok(MessageTypes::EraseBurnStart),
-            x if x == MessageTypes::FlashBurnData as u8 => Ok(MessageTypes::FlashBurnData),
-            x if x == MessageTypes::FlashRead as u8 => Ok(MessageTypes::FlashRead),
-            x if x == MessageTypes::UnknownORInfo as u8 => Ok(MessageTypes::UnknownORInfo),
-            x if x == MessageTypes::DeviceCommand as u8 => Ok(MessageTypes::DeviceCommand),
-            _ => Err(()),
+            MessageTypes::DeviceCommand => 0x00,
+            MessageTypes::FlashRead => 0x03,
+            MessageTypes::Sync => 0x50,
+            MessageTypes::StartProgrammer => 0x53,
+            MessageTypes::ProgrammerRunning => 0x54,
+            MessageTypes::ProgrammerStart => 0x55,
+            MessageTypes::ProgrammerInit => 0x60,
+            MessageTypes::EraseBurnStart => 0x61,
+            MessageTypes::FlashBurnData => 0x62,
+            MessageTypes::FlashCommand => 0x65,
+            MessageTypes::UnknownORInfo => 0x66,
+            MessageTypes::Unknown(raw) => raw,
         }
     }
 }
+// Frame layout on the wire:
+// 0 - SYNC (0xBE)
+// 1 - Message type
+// 2 - Sequence number; replies echo the sequence of the request they answer
+// 3 - Payload length
+// 4.. - Payload
+// N - Checksum
+// Fields are private so a message can only be made through new() or parse(), which keep len and checksum honest
 #[derive(Debug, PartialEq, Clone)]
 pub struct BesMessage {
-    pub sync: u8,
-    pub type1: MessageTypes,
-    pub payload: Vec<u8>,
-    pub checksum: u8,
+    type1: MessageTypes,
+    seq: u8,
+    len: u8,
+    payload: Vec<u8>,
+    checksum: u8,
 }
 
 impl BesMessage {
-    pub fn to_vec(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = vec![];
-        result.push(self.sync);
-        result.push(self.type1 as u8);
-        result.append(&mut self.payload.clone());
-        result.push(self.checksum);
-        result
-    }
-    pub fn set_checksum(&mut self) {
-        let mut v = self.to_vec();
-        v.pop();
-        self.checksum = calculate_message_checksum(&v);
-    }
-}
-
-impl From<Vec<u8>> for BesMessage {
-    fn from(d: Vec<u8>) -> Self {
+    pub fn new(type1: MessageTypes, seq: u8, payload: Vec<u8>) -> Self {
+        assert!(
+            payload.len() <= u8::MAX as usize,
+            "BES message payload of {} bytes does not fit the length byte",
+            payload.len()
+        );
         let mut msg = BesMessage {
-            sync: d[0],
-            type1: MessageTypes::Sync,
-            payload: vec![],
-            checksum: d[d.len() - 1],
-        };
-
-        match d[1].try_into() {
-            Ok(type1) => msg.type1 = type1,
-            Err(_) => {
-                println!("Unknown packet type 0x{:02X}", d[1]);
-            }
+            type1,
+            seq,
+            len: payload.len() as u8,
+            payload,
+            checksum: 0x00,
         };
-
-        msg.payload = d[2..d.len() - 1].to_vec();
-
+        let mut v = msg.to_vec();
+        v.pop();
+        msg.checksum = calculate_message_checksum(&v);
         msg
     }
+    pub fn parse(packet: &[u8]) -> Result<Self, BESLinkError> {
+        if packet.len() < 5 || packet[0] != BES_SYNC {
+            return Err(BESLinkError::BadFrameLength {
+                failed_packet: packet.to_vec(),
+                got: packet.len(),
+                wanted: 5,
+            });
+        }
+        let declared_len = packet[3] as usize;
+        if packet.len() != declared_len + 5 {
+            return Err(BESLinkError::BadFrameLength {
+                failed_packet: packet.to_vec(),
+                got: packet.len(),
+                wanted: declared_len + 5,
+            });
+        }
+        validate_packet_checksum(packet)?;
+
+        let type1 = MessageTypes::from(packet[1]);
+        if let MessageTypes::Unknown(raw) = type1 {
+            warn!("Unknown packet type 0x{raw:02X} {packet:02X?}");
+        }
+        Ok(BesMessage {
+            type1,
+            seq: packet[2],
+            len: packet[3],
+            payload: packet[4..packet.len() - 1].to_vec(),
+            checksum: packet[packet.len() - 1],
+        })
+    }
+    pub fn type1(&self) -> MessageTypes {
+        self.type1
+    }
+    pub fn seq(&self) -> u8 {
+        self.seq
+    }
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = vec![BES_SYNC, self.type1.into(), self.seq, self.len];
+        result.extend_from_slice(&self.payload);
+        result.push(self.checksum);
+        result
+    }
 }
 
-pub fn send_message(serial_port: &mut Box<dyn SerialPort>, msg: BesMessage) -> std::io::Result<()> {
+pub fn send_message(transport: &mut dyn BesTransport, msg: BesMessage) -> std::io::Result<()> {
     let packet = msg.to_vec();
-    match serial_port.write_all(packet.as_slice()) {
+    match transport.write_all(packet.as_slice()) {
         Ok(_) => {
             debug!("Wrote {} bytes", packet.len());
             info!("Sent message type {:?} {:X?}", msg.type1, msg.to_vec());
-            let _ = serial_port.flush();
+            let _ = transport.flush();
             Ok(())
         }
         Err(e) => {
@@ -102,13 +153,14 @@ pub fn send_message(serial_port: &mut Box<dyn SerialPort>, msg: BesMessage) -> s
     }
 }
 pub fn read_message_with_trailing_data(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     expected_data_len: usize,
+    deadline: &Deadline,
 ) -> Result<(BesMessage, Vec<u8>), BESLinkError> {
     //First read the packet; then read the expected_raw_bytes from the uart
     //TODO for now assuming the 0x03 code for response
 
-    let response = read_message(serial_port)?;
+    let response = read_message(transport, deadline)?;
     if response.type1 != MessageTypes::FlashRead {
         error!("Bad packet type: {:?}", response.type1);
         return Err(BESLinkError::InvalidArgs);
@@ -117,7 +169,10 @@ pub fn read_message_with_trailing_data(
     let mut buffer: [u8; FLASH_BUFFER_SIZE] = [0; FLASH_BUFFER_SIZE];
 
     while packet.len() < expected_data_len {
-        match serial_port.read(&mut buffer) {
+        deadline.check()?;
+        // Never ask for more than is owed, anything after the data belongs to the next reply
+        let wanted = (expected_data_len - packet.len()).min(buffer.len());
+        match transport.read(&mut buffer[..wanted]) {
             Ok(n) => {
                 if n > 0 {
                     packet.extend(&buffer[0..n]);
@@ -127,7 +182,7 @@ pub fn read_message_with_trailing_data(
             }
             Err(e) => {
                 if e.kind() != TimedOut {
-                    println!("Error reading packet header {e:?}");
+                    warn!("Error reading packet {e:?}");
                     return Err(BESLinkError::from(e));
                 }
             }
@@ -135,14 +190,18 @@ pub fn read_message_with_trailing_data(
     }
     Ok((response, packet))
 }
-pub fn read_message(serial_port: &mut Box<dyn SerialPort>) -> Result<BesMessage, BESLinkError> {
+pub fn read_message(
+    transport: &mut dyn BesTransport,
+    deadline: &Deadline,
+) -> Result<BesMessage, BESLinkError> {
     //
     let mut packet: Vec<u8> = vec![];
     let mut packet_len: usize = 4; //Start expectations at the minimum
     let mut buffer: [u8; 1] = [0; 1];
 
     while packet.len() < packet_len {
-        match serial_port.read(&mut buffer) {
+        deadline.check()?;
+        match transport.read(&mut buffer) {
             Ok(n) => {
                 if n == 1 {
                     // Only grab if actual data
@@ -153,20 +212,17 @@ pub fn read_message(serial_port: &mut Box<dyn SerialPort>) -> Result<BesMessage,
             }
             Err(e) => {
                 if e.kind() != TimedOut {
-                    println!("Error reading packet header {e:?}");
+                    warn!("Error reading packet {e:?}");
                     return Err(BESLinkError::from(e));
                 }
             }
         }
         if packet.len() == 4 && packet_len == 4 {
-            packet_len = (0x05 + packet[3]) as usize;
+            // Worked out in usize, as a length byte of 0xFB or more overflows a u8
+            packet_len = 5 + packet[3] as usize;
         }
-        //TODO timeout
-    }
-    match validate_packet_checksum(&packet) {
-        Ok(_) => Ok(BesMessage::from(packet)),
-        Err(e) => Err(e),
     }
+    BesMessage::parse(&packet)
 }
 pub fn validate_packet_checksum(packet: &[u8]) -> Result<(), BESLinkError> {
     let checksum = calculate_message_checksum(&packet[0..packet.len() - 1]);
@@ -192,26 +248,69 @@ pub fn calculate_message_checksum(packet: &[u8]) -> u8 {
 
 #[cfg(test)]
 mod tests {
+    use crate::beslink::memory_transport::MemoryTransport;
     use crate::beslink::message::calculate_message_checksum;
+    use crate::beslink::message::read_message;
     use crate::beslink::message::validate_packet_checksum;
-    use crate::beslink::{BesMessage, MessageTypes, BES_SYNC};
+    use crate::beslink::{BESLinkError, BesMessage, MessageTypes, Stage, Timeouts, BES_SYNC};
 
     #[test]
-    fn test_from() {
+    fn test_parse() {
         let expected_vec: Vec<u8> = vec![BES_SYNC, 0x55, 0x3A, 0x00, 0xB2];
-        let msg = BesMessage::from(expected_vec.clone());
+        let msg = BesMessage::parse(&expected_vec).unwrap();
+        assert_eq!(msg.type1(), MessageTypes::ProgrammerStart);
+        assert_eq!(msg.seq(), 0x3A);
+        assert!(msg.payload().is_empty());
         assert_eq!(expected_vec, msg.to_vec())
     }
     #[test]
+    fn test_read_longest_frame() {
+        let mut packet: Vec<u8> = vec![BES_SYNC, 0x03, 0x00, 0xFF];
+        packet.extend([0x5A; 0xFF]);
+        packet.push(calculate_message_checksum(&packet));
+        let mut transport = MemoryTransport::new();
+        transport.push_incoming(&packet);
+        let msg = read_message(
+            &mut transport,
+            &Timeouts::default().deadline(Stage::Command),
+        )
+        .unwrap();
+        assert_eq!(msg.payload().len(), 0xFF);
+        assert_eq!(msg.to_vec(), packet);
+    }
+    #[test]
+    fn test_parse_keeps_unknown_type() {
+        let packet: Vec<u8> = vec![BES_SYNC, 0x7A, 0x01, 0x00, 0xC6];
+        let msg = BesMessage::parse(&packet).unwrap();
+        assert_eq!(msg.type1(), MessageTypes::Unknown(0x7A));
+        assert_ne!(msg.type1(), MessageTypes::Sync);
+        assert_eq!(packet, msg.to_vec());
+    }
+    #[test]
+    fn test_parse_rejects_bad_length() {
+        // Declares 3 bytes of payload but only carries 2
+        let packet: Vec<u8> = vec![BES_SYNC, 0x65, 0x02, 0x03, 0x11, 0x00, 0xC6];
+        assert!(matches!(
+            BesMessage::parse(&packet),
+            Err(BESLinkError::BadFrameLength {
+                got: 7,
+                wanted: 8,
+                ..
+            })
+        ));
+        let packet: Vec<u8> = vec![BES_SYNC, 0x65, 0x02, 0x01, 0x11, 0xC9];
+        assert!(matches!(
+            BesMessage::parse(&packet),
+            Err(BESLinkError::BadChecksumError { .. })
+        ));
+    }
+    #[test]
     fn test_to_vec() {
-        let test = BesMessage {
-            sync: BES_SYNC,
-            type1: MessageTypes::ProgrammerStart,
-            payload: vec![0x3A, 0x00],
-            checksum: 0xB2,
-        };
+        let test = BesMessage::new(MessageTypes::ProgrammerStart, 0x3A, vec![]);
         let expected_vec: Vec<u8> = vec![BES_SYNC, 0x55, 0x3A, 0x00, 0xB2];
-        assert_eq!(expected_vec, test.to_vec())
+        assert_eq!(expected_vec, test.to_vec());
+        let test = BesMessage::new(MessageTypes::FlashCommand, 0x03, vec![0x12]);
+        assert_eq!(vec![BES_SYNC, 0x65, 0x03, 0x01, 0x12, 0xC6], test.to_vec());
     }
     #[test]
     fn test_calculate_packet_checksum() {
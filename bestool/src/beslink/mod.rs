@@ -1,28 +1,102 @@
+mod baud;
+mod blank_check;
 mod bootloader;
+mod capture;
+mod chip;
+mod commands;
+mod decode;
 mod errors;
+mod firmware_image;
 mod helper_sync_and_load_programmer;
+mod jedec;
 mod memory_info;
+#[cfg(test)]
+mod memory_transport;
 mod message;
+mod programmer_image;
 mod read_flash;
 mod reboot;
+mod reset;
+#[cfg(test)]
+pub(crate) mod simulator;
 mod sync;
-mod test_port;
+mod tcp_transport;
+mod timeouts;
+mod transport;
+mod verify;
 mod write_flash;
 
+// BES2300 values; other parts get theirs from their ChipProfile
 pub const BES_PROGRAMMING_BAUDRATE: u32 = 921_600;
 pub const BES_SYNC: u8 = 0xBE;
 pub const FLASH_BUFFER_SIZE: usize = 0x8000;
+// The flash is mapped here; burn and read addresses are absolute
+pub const BES_FLASH_BASE: usize = 0x3C00_0000;
 
+pub use baud::negotiate_baud_rate;
+pub use blank_check::BlankChecker;
+pub use blank_check::NonBlankRange;
 pub use bootloader::load_programmer_runtime_binary_blob;
+pub use bootloader::read_programmer;
 pub use bootloader::start_programmer_runtime_binary_blob;
+pub use capture::load_capture;
+pub use capture::CaptureEvent;
+pub use capture::RecordingTransport;
+pub use capture::ReplayTransport;
+pub use chip::chip_baud_rate;
+pub use chip::chip_names;
+pub use chip::find_chip;
+pub use chip::resolve_chip;
+pub use chip::ChipProfile;
+pub use commands::check_status;
+pub use commands::BurnAck;
+pub use commands::EraseBurnStart;
+pub use commands::FlashBurnCommit;
+pub use commands::FlashGetId;
+pub use commands::FlashGetUniqueId;
+pub use commands::FlashId;
+pub use commands::FlashUniqueId;
+pub use commands::MemoryRead;
+pub use commands::ProgrammerInfo;
+pub use commands::Reboot;
+pub use commands::SetBaudRate;
+pub use commands::StartProgrammer;
+pub use commands::SyncInfo;
+pub use commands::BURN_ACK_OK;
+pub use commands::SYNC_STATE_CONFIRMED;
+pub use decode::DecodedItem;
+pub use decode::Direction;
+pub use decode::FrameDecoder;
 pub use errors::BESLinkError;
 pub use helper_sync_and_load_programmer::helper_sync_and_load_programmer;
+pub use helper_sync_and_load_programmer::DeviceInfo;
+pub use helper_sync_and_load_programmer::ProgrammingOptions;
+pub use jedec::detected_flash_size;
+pub use jedec::flash_sector_size;
+pub use jedec::lookup_flash_part;
+pub use jedec::manufacturer_name;
 pub use memory_info::query_memory_info;
 
+pub use firmware_image::{flash_regions, load_image_segments, ImageSegment};
 pub use message::send_message;
 pub use message::BesMessage;
 pub use message::MessageTypes;
-pub use read_flash::read_flash_data;
+pub use programmer_image::ProgrammerImage;
+pub use read_flash::read_flash_in_segments;
+pub use reboot::reboot_into_app;
 pub use reboot::send_device_reboot;
+pub use reset::run_reset_sequence;
+pub use reset::ResetSequences;
+pub use reset::ResetStep;
 pub use sync::sync;
+pub use tcp_transport::TcpTransport;
+pub use timeouts::Deadline;
+pub use timeouts::Stage;
+pub use timeouts::Timeouts;
+pub use transport::BesTransport;
+pub use verify::verify_flash;
 pub use write_flash::burn_image_to_flash;
+pub use write_flash::burn_image_to_flash_incremental;
+pub use write_flash::check_burn_address;
+pub use write_flash::check_flash_range;
+pub use write_flash::erase_flash;
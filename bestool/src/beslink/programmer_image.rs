@@ -0,0 +1,119 @@
+use crate::beslink::{BESLinkError, StartProgrammer};
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+// Programmer blobs as shipped with the vendor tool: a header starting with this magic,
+// the code that is sent to the device, then the RAM address the code is loaded to.
+// The header does not say how long it is; every blob seen so far has the same 0x41C byte one,
+// so that is taken as part of the format and the fields below are checked to make sure a blob follows it
+const PROGRAMMER_MAGIC: [u8; 4] = [0x1C, 0xEC, 0x57, 0xBE];
+const PROGRAMMER_CODE_OFFSET: usize = 0x41C;
+const LOAD_ADDRESS_SIZE: usize = 4;
+// A RAM address in the header (0x2002_2A5C in the BES2300 blob); what it points at is not known,
+// but it always lands inside the loaded code
+const HEADER_CODE_ADDRESS_OFFSET: usize = 0xC;
+// The code opens with a small table holding the address it was linked to run from, which matches the trailer
+const CODE_LOAD_ADDRESS_OFFSET: usize = 0xC;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProgrammerImage {
+    pub load_address: u32,
+    pub code: Vec<u8>,
+}
+
+impl ProgrammerImage {
+    pub fn parse(blob: &[u8]) -> Result<Self, BESLinkError> {
+        if !blob.starts_with(&PROGRAMMER_MAGIC) {
+            return Err(BESLinkError::BadProgrammerImage {
+                reason: "missing the 1CEC57BE magic",
+            });
+        }
+        if blob.len() <= PROGRAMMER_CODE_OFFSET + LOAD_ADDRESS_SIZE {
+            return Err(BESLinkError::BadProgrammerImage {
+                reason: "too short to hold any code",
+            });
+        }
+        let (code, load_address) = blob[PROGRAMMER_CODE_OFFSET..]
+            .split_at(blob.len() - PROGRAMMER_CODE_OFFSET - LOAD_ADDRESS_SIZE);
+        let load_address = u32::from_le_bytes(load_address.try_into().unwrap());
+        let code_range = load_address as usize..load_address as usize + code.len();
+        if !code_range.contains(&(read_u32(blob, HEADER_CODE_ADDRESS_OFFSET) as usize)) {
+            return Err(BESLinkError::BadProgrammerImage {
+                reason: "its header does not point into its code",
+            });
+        }
+        if code.len() < CODE_LOAD_ADDRESS_OFFSET + 4
+            || read_u32(code, CODE_LOAD_ADDRESS_OFFSET) != load_address
+        {
+            return Err(BESLinkError::BadProgrammerImage {
+                reason: "its code does not start with its load address",
+            });
+        }
+        Ok(ProgrammerImage {
+            load_address,
+            code: code.to_vec(),
+        })
+    }
+
+    pub fn start_request(&self) -> StartProgrammer {
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        StartProgrammer {
+            load_address: self.load_address,
+            len: self.code.len() as u32,
+            crc32: crc.checksum(&self.code),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::programmer_image::ProgrammerImage;
+    use crate::beslink::{BESLinkError, StartProgrammer};
+
+    #[test]
+    fn test_parse_embedded_programmer() {
+        let image = ProgrammerImage::parse(include_bytes!("../../../programmer.bin")).unwrap();
+        // The values the StartProgrammer message used to have hard-coded
+        assert_eq!(
+            image.start_request(),
+            StartProgrammer {
+                load_address: 0x2001_061C,
+                len: 0x1_2478,
+                crc32: 0x9EB9_D78A,
+            }
+        );
+    }
+    #[test]
+    fn test_parse_rejects_other_files() {
+        assert!(matches!(
+            ProgrammerImage::parse(&[0x7F, b'E', b'L', b'F', 0, 0, 0, 0]),
+            Err(BESLinkError::BadProgrammerImage { .. })
+        ));
+        assert!(matches!(
+            ProgrammerImage::parse(&[0x1C, 0xEC, 0x57, 0xBE, 0, 0, 0, 0]),
+            Err(BESLinkError::BadProgrammerImage { .. })
+        ));
+    }
+    #[test]
+    fn test_parse_rejects_other_layouts() {
+        let blob = include_bytes!("../../../programmer.bin");
+        // A header pointing somewhere else in RAM
+        let mut moved = blob.to_vec();
+        moved[0xC..0x10].copy_from_slice(&0x2000_0000u32.to_le_bytes());
+        assert!(matches!(
+            ProgrammerImage::parse(&moved),
+            Err(BESLinkError::BadProgrammerImage { .. })
+        ));
+        // A longer header, which would otherwise be sent to the device as the start of the code
+        let mut longer = blob[..0x41C].to_vec();
+        longer.extend([0; 0x10]);
+        longer.extend(&blob[0x41C..]);
+        assert!(matches!(
+            ProgrammerImage::parse(&longer),
+            Err(BESLinkError::BadProgrammerImage { .. })
+        ));
+    }
+}
@@ -1,58 +1,143 @@
 use crate::beslink::message::read_message_with_trailing_data;
 use crate::beslink::{
-    send_message, BESLinkError, BesMessage, MessageTypes, BES_SYNC, FLASH_BUFFER_SIZE,
+    helper_sync_and_load_programmer, send_device_reboot, send_message, BESLinkError, BesTransport,
+    MemoryRead, ProgrammingOptions, Stage, Timeouts, FLASH_BUFFER_SIZE,
 };
-use serialport::SerialPort;
 use std::time::Duration;
 use tracing::{info, warn};
 
+//The main bootloader wasn't super designed to allow reading the flash;
+// but they shipped a debugging memory read that will try and dump memory content out to the uart basically.
+// But as this is a "debug" message; it doesnt seem to reset the watchdog
+// This means that reads larger than about 2MB will fail randomly when the watchdog trips
+// To work around this, we read 1MB segments with a device reset between the reads
+const MAX_READ_BEFORE_RESET: usize = 1024 * 1024;
+
+// The debug read has no checksum, so every chunk is read until two reads in a row agree
+const MAX_CHUNK_READS: usize = 10;
+
+// Expects the programmer to be running and leaves it running afterwards, loading it again with the same options
+// between segments; each segment is handed to the sink along with its address as soon as it has been read
+pub fn read_flash_in_segments(
+    transport: &mut dyn BesTransport,
+    address: usize,
+    length: usize,
+    options: &ProgrammingOptions,
+    timeouts: &Timeouts,
+    mut sink: impl FnMut(usize, Vec<u8>) -> Result<(), BESLinkError>,
+) -> Result<(), BESLinkError> {
+    let mut done = 0;
+    let mut retried_chunks = 0;
+    while done < length {
+        if done > 0 {
+            info!("Resetting device before reading on");
+            send_device_reboot(transport, timeouts)?;
+            helper_sync_and_load_programmer(transport, options, timeouts)?;
+        }
+        let segment_length = (length - done).min(MAX_READ_BEFORE_RESET);
+        let pos = address + done;
+        info!(
+            "===== Preparing to read flash from 0x{:X} ({}%) to 0x{:X} ({}%) =====",
+            pos,
+            done * 100 / length,
+            pos + segment_length,
+            (done + segment_length) * 100 / length,
+        );
+        let (segment, retried) = read_flash_data(transport, pos, segment_length, timeouts)?;
+        retried_chunks += retried;
+        sink(pos, segment)?;
+        done += segment_length;
+    }
+    let chunks = length.div_ceil(FLASH_BUFFER_SIZE / 2);
+    if retried_chunks > 0 {
+        warn!("{retried_chunks} of {chunks} chunks had to be read again before two reads agreed");
+    } else {
+        info!("All {chunks} chunks read back the same twice");
+    }
+    Ok(())
+}
+
+// Also returns how many chunks needed more than two reads
 pub fn read_flash_data(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     address: usize,
     length: usize,
-) -> Result<Vec<u8>, BESLinkError> {
+    timeouts: &Timeouts,
+) -> Result<(Vec<u8>, usize), BESLinkError> {
     let mut result = vec![];
-    let mut tries = 0;
+    let mut retried_chunks = 0;
     while result.len() < length {
         let pos = address + result.len();
-        match read_flash_chunk(serial_port, pos) {
+        let (chunk, reads) = read_flash_chunk_checked(transport, pos, timeouts)?;
+        if reads > 2 {
+            retried_chunks += 1;
+        }
+        result.extend_from_slice(&chunk);
+        info!("Read flash from 0x{:X} to 0x{:X}", pos, pos + chunk.len());
+    }
+    result.resize(length, 0xFF);
+    Ok((result, retried_chunks))
+}
+
+// Returns the chunk once two consecutive reads match, along with how many reads that took
+fn read_flash_chunk_checked(
+    transport: &mut dyn BesTransport,
+    address: usize,
+    timeouts: &Timeouts,
+) -> Result<(Vec<u8>, usize), BESLinkError> {
+    let mut previous: Option<Vec<u8>> = None;
+    let mut reads = 0;
+    loop {
+        reads += 1;
+        match read_flash_chunk(transport, address, timeouts) {
             Ok(chunk) => {
-                result.extend_from_slice(&chunk);
                 std::thread::sleep(Duration::from_millis(10)); // Try to yield to let watch dog reset
-                info!("Read flash from 0x{:X} to 0x{:X}", pos, pos + chunk.len());
+                if previous.as_ref() == Some(&chunk) {
+                    return Ok((chunk, reads));
+                }
+                if previous.is_some() {
+                    warn!(
+                        "Two reads of the chunk at 0x{:X} differ, reading again",
+                        address
+                    );
+                }
+                previous = Some(chunk);
             }
             Err(e) => {
                 warn!("Error {}", e);
-                tries += 1;
-                if tries > 10 {
+                if reads >= MAX_CHUNK_READS {
                     return Err(e);
                 }
+                // Whatever is left of a broken reply would otherwise be taken as the start of the next one
+                let _ = transport.clear_buffers();
+                previous = None;
             }
         }
+        if reads >= MAX_CHUNK_READS {
+            return Err(BESLinkError::ReadUnstable {
+                address,
+                reads: MAX_CHUNK_READS,
+            });
+        }
     }
-    result.resize(length, 0xFF);
-    Ok(result)
 }
 
 //
 
 fn read_flash_chunk(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     address: usize,
+    timeouts: &Timeouts,
 ) -> Result<Vec<u8>, BESLinkError> {
-    let mut cfg_data_1 = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::FlashRead,
-        payload: vec![0x05, 0x08], // No idea what these two mean yet
-        checksum: 0xF6,
-    };
     let chunk_size = FLASH_BUFFER_SIZE / 2;
-    cfg_data_1.payload.extend((address as u32).to_le_bytes());
-    cfg_data_1.payload.extend((chunk_size as u32).to_le_bytes());
-    cfg_data_1.set_checksum();
+    let read_request = MemoryRead {
+        addr: address as u32,
+        len: chunk_size as u32,
+    };
 
-    send_message(serial_port, cfg_data_1)?;
+    send_message(transport, read_request.to_message())?;
     //response is 4102 bytes total = 4096 (0x1000)
-    let (_, payload) = read_message_with_trailing_data(serial_port, chunk_size)?;
+    let (_, payload) =
+        read_message_with_trailing_data(transport, chunk_size, &timeouts.deadline(Stage::Read))?;
     Ok(payload)
 }
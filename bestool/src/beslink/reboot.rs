@@ -1,29 +1,42 @@
 //Reboot message:
-//0 - SYNC
-//1 - 0
-//2 - ?? 0/1/2
-//3 - 0x05 -- Message length+5??
-//4 - 0xF1
+//Type 0 (DeviceCommand), payload 0xF1
 
-use crate::beslink::{send_message, sync, BESLinkError, BesMessage, MessageTypes, BES_SYNC};
-use serialport::SerialPort;
+use crate::beslink::{
+    run_reset_sequence, send_message, sync, BESLinkError, BesMessage, BesTransport, MessageTypes,
+    ProgrammingOptions, Reboot, Stage, Timeouts,
+};
 use tracing::info;
 
 pub fn send_device_reboot(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
+    timeouts: &Timeouts,
 ) -> Result<BesMessage, BESLinkError> {
-    let mut device_reboot_message = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::DeviceCommand,
-        payload: vec![0x00, 0x01, 0xF1],
-        checksum: 0xEB,
-    };
-    device_reboot_message.set_checksum();
+    let device_reboot_message = Reboot.to_message();
 
     info!(
         "Sent device reboot message, {:X?}",
         device_reboot_message.to_vec()
     );
-    send_message(serial_port, device_reboot_message)?;
-    sync(serial_port, MessageTypes::DeviceCommand)
+    send_message(transport, device_reboot_message)?;
+    let response = sync(
+        transport,
+        MessageTypes::DeviceCommand,
+        &timeouts.deadline(Stage::Command),
+    )?;
+    Ok(response)
+}
+
+// The reboot that ends a command; reboots part way through (such as between read segments) use
+// send_device_reboot so the reset lines are left alone until the device is done with
+pub fn reboot_into_app(
+    transport: &mut dyn BesTransport,
+    options: &ProgrammingOptions,
+    timeouts: &Timeouts,
+) -> Result<BesMessage, BESLinkError> {
+    let response = send_device_reboot(transport, timeouts)?;
+    // Make sure the fixture lets go of any boot strap pins, so the app is what comes up
+    if let Some(reset) = &options.reset {
+        run_reset_sequence(transport, &reset.into_app)?;
+    }
+    Ok(response)
 }
@@ -0,0 +1,163 @@
+use crate::beslink::{BESLinkError, BesTransport};
+use std::time::Duration;
+use tracing::info;
+
+// For fixtures that wire the uart control lines to the board's reset (and boot strap) pins.
+// true asserts the line, which on most adapters drives the pin low
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ResetStep {
+    Dtr(bool),
+    Rts(bool),
+    Wait(Duration),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResetSequences {
+    // Run before and, until the ROM answers, during the sync
+    pub into_rom: Vec<ResetStep>,
+    // Run after the reboot command at the end of programming
+    pub into_app: Vec<ResetStep>,
+}
+
+const PRESETS: &[(&str, &str, &str)] = &[
+    // DTR wired to reset
+    ("dtr-pulse", "D1,W100,D0", "D1,W100,D0"),
+    // RTS wired to reset
+    ("rts-pulse", "R1,W100,R0", "R1,W100,R0"),
+    // Two transistor auto reset: RTS to reset, DTR to the boot strap, as on ESP style boards
+    (
+        "rts-dtr-classic",
+        "D0,R1,W100,D1,R0,W50,D0",
+        "D0,R1,W100,R0",
+    ),
+];
+
+impl ResetSequences {
+    // Either a preset name, or custom steps such as D1,W100,D0 (DTR on, wait 100ms, DTR off)
+    // optionally followed by ;<steps> for the reset into the app, which otherwise repeats the first
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some((_, into_rom, into_app)) = PRESETS.iter().find(|(name, ..)| *name == value) {
+            return Ok(ResetSequences {
+                into_rom: parse_steps(into_rom)?,
+                into_app: parse_steps(into_app)?,
+            });
+        }
+        let (into_rom, into_app) = value.split_once(';').unwrap_or((value, value));
+        Ok(ResetSequences {
+            into_rom: parse_steps(into_rom)?,
+            into_app: parse_steps(into_app)?,
+        })
+    }
+}
+
+fn parse_steps(steps: &str) -> Result<Vec<ResetStep>, String> {
+    steps
+        .split(',')
+        .map(|step| {
+            let step = step.trim();
+            match (step.get(..1), step.get(1..)) {
+                (Some("D" | "d"), Some("0")) => Ok(ResetStep::Dtr(false)),
+                (Some("D" | "d"), Some("1")) => Ok(ResetStep::Dtr(true)),
+                (Some("R" | "r"), Some("0")) => Ok(ResetStep::Rts(false)),
+                (Some("R" | "r"), Some("1")) => Ok(ResetStep::Rts(true)),
+                (Some("W" | "w"), Some(ms)) => ms
+                    .parse()
+                    .map(|ms| ResetStep::Wait(Duration::from_millis(ms)))
+                    .map_err(|_| format!("{step} is not a wait in milliseconds")),
+                _ => Err(format!(
+                    "{step} is not a reset step (D0/D1, R0/R1 or W<ms>), and {} are the presets",
+                    PRESETS
+                        .iter()
+                        .map(|(name, ..)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            }
+        })
+        .collect()
+}
+
+pub fn run_reset_sequence(
+    transport: &mut dyn BesTransport,
+    steps: &[ResetStep],
+) -> Result<(), BESLinkError> {
+    info!("Resetting the board with the control lines");
+    for step in steps {
+        match step {
+            ResetStep::Dtr(level) => transport.set_dtr(*level)?,
+            ResetStep::Rts(level) => transport.set_rts(*level)?,
+            ResetStep::Wait(duration) => std::thread::sleep(*duration),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::reset::{run_reset_sequence, ResetSequences, ResetStep};
+    use crate::beslink::simulator::{DeviceSimulator, SimState};
+    use crate::beslink::{
+        helper_sync_and_load_programmer, BESLinkError, ProgrammingOptions, Stage, Timeouts,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_reset_sequences() {
+        let pulse = ResetSequences::parse("dtr-pulse").unwrap();
+        assert_eq!(
+            pulse.into_rom,
+            vec![
+                ResetStep::Dtr(true),
+                ResetStep::Wait(Duration::from_millis(100)),
+                ResetStep::Dtr(false)
+            ]
+        );
+        let custom = ResetSequences::parse("R1,W20,R0;d1,w5,d0").unwrap();
+        assert_eq!(
+            custom.into_rom[1],
+            ResetStep::Wait(Duration::from_millis(20))
+        );
+        assert_eq!(custom.into_app[0], ResetStep::Dtr(true));
+        assert_eq!(
+            ResetSequences::parse("R1,W20,R0").unwrap().into_app.len(),
+            3
+        );
+        assert!(ResetSequences::parse("dtr-wiggle").is_err());
+        assert!(ResetSequences::parse("W1s").is_err());
+    }
+    #[test]
+    fn test_reset_puts_device_back_in_rom() {
+        let timeouts = Timeouts::default();
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        assert_eq!(sim.state, SimState::Programmer);
+        let reset = ResetSequences::parse("dtr-pulse").unwrap();
+        run_reset_sequence(&mut sim, &reset.into_rom).unwrap();
+        assert_eq!(sim.state, SimState::Rom);
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+    }
+    #[test]
+    fn test_sync_resets_into_rom() {
+        let timeouts = Timeouts {
+            sync: Duration::from_millis(300),
+            ..Timeouts::default()
+        };
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        // Still running the programmer, so nothing comes back to a sync until the board is reset
+        assert!(matches!(
+            helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts),
+            Err(BESLinkError::Timeout { stage: Stage::Sync })
+        ));
+        let options = ProgrammingOptions {
+            reset: Some(ResetSequences::parse("dtr-pulse").unwrap()),
+            ..ProgrammingOptions::default()
+        };
+        helper_sync_and_load_programmer(&mut sim, &options, &timeouts).unwrap();
+        assert_eq!(sim.state, SimState::Programmer);
+        assert_eq!(sim.reboots, 0);
+    }
+}
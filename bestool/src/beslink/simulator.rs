@@ -0,0 +1,475 @@
+use crate::beslink::message::calculate_message_checksum;
+use crate::beslink::{
+    BesMessage, BesTransport, MessageTypes, BES_FLASH_BASE, BES_PROGRAMMING_BAUDRATE, BES_SYNC,
+    FLASH_BUFFER_SIZE,
+};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use std::collections::VecDeque;
+use std::io::ErrorKind::TimedOut;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// Simulated BES2300 that speaks the protocol captured in Docs/Notes.md
+// It answers synchronously; every write is parsed straight away and any replies are queued for the next read
+// The flash array is addressed the same as the real part, starting at SIM_FLASH_BASE
+
+pub const SIM_FLASH_BASE: usize = BES_FLASH_BASE;
+pub const SIM_FLASH_SIZE: usize = 4 * 1024 * 1024;
+// The GD25LQ32 the simulator claims to have erases 4KiB sectors
+pub const SIM_SECTOR_SIZE: usize = 0x1000;
+
+const ROM_SYNC_ADVERTISEMENT: [u8; 3] = [0x00, 0x00, 0x01];
+const PROGRAMMER_INIT_REPLY: [u8; 6] = [0x03, 0x01, 0x00, 0x90, 0x00, 0x00];
+const FLASH_ID: [u8; 3] = [0xC8, 0x60, 0x16];
+const FLASH_UNIQUE_ID: [u8; 16] = [
+    0x30, 0x31, 0x32, 0x33, 0x49, 0x0A, 0x88, 0x4A, 0x41, 0x53, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+const BURN_COMMIT_MAGIC: [u8; 4] = [0x1C, 0xEC, 0x57, 0xBE];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SimState {
+    Rom,
+    ProgrammerLoaded,
+    Programmer,
+}
+
+// Raw (unframed) bytes the device is waiting for after certain commands
+enum Trailing {
+    ProgrammerImage { length: usize, crc: u32 },
+    BurnChunk { seq: u8, chunk: usize, crc: u32 },
+}
+
+pub struct DeviceSimulator {
+    pub flash: Vec<u8>,
+    pub state: SimState,
+    pub reboots: usize,
+    pub burned_chunks: usize,
+    // Reported when the sync is confirmed; 0x0100 is the BES2300
+    pub rom_version: u16,
+    // Erases cover whole sectors, like a real part, taking anything else in them with the burn's range
+    pub sector_size: usize,
+    // How many of the coming flash read dumps get a byte mangled on the way out, like a noisy uart
+    pub corrupt_reads: usize,
+    // Whether the programmer takes the set baud rate command; it is not known if real ones do
+    pub accepts_baud_change: bool,
+    pub device_baud_rate: u32,
+    // Takes effect once the acknowledgement has gone out, i.e. on the next write
+    pending_baud_rate: Option<u32>,
+    host_baud_rate: u32,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    trailing: Option<Trailing>,
+    programmer_announce: Option<(usize, u32)>,
+    burn_window: Option<(usize, usize)>,
+}
+
+impl DeviceSimulator {
+    pub fn new() -> Self {
+        let mut sim = DeviceSimulator {
+            flash: vec![0xFF; SIM_FLASH_SIZE],
+            state: SimState::Rom,
+            reboots: 0,
+            burned_chunks: 0,
+            rom_version: 0x0100,
+            sector_size: SIM_SECTOR_SIZE,
+            corrupt_reads: 0,
+            accepts_baud_change: false,
+            device_baud_rate: BES_PROGRAMMING_BAUDRATE,
+            pending_baud_rate: None,
+            host_baud_rate: BES_PROGRAMMING_BAUDRATE,
+            input: vec![],
+            output: VecDeque::new(),
+            trailing: None,
+            programmer_announce: None,
+            burn_window: None,
+        };
+        sim.boot();
+        sim
+    }
+
+    fn boot(&mut self) {
+        self.state = SimState::Rom;
+        self.device_baud_rate = BES_PROGRAMMING_BAUDRATE;
+        self.pending_baud_rate = None;
+        self.input.clear();
+        self.trailing = None;
+        self.programmer_announce = None;
+        self.burn_window = None;
+        self.reply(MessageTypes::Sync, 0x00, &ROM_SYNC_ADVERTISEMENT);
+    }
+
+    fn reply(&mut self, type1: MessageTypes, seq: u8, payload: &[u8]) {
+        let msg = BesMessage::new(type1, seq, payload.to_vec());
+        self.output.extend(msg.to_vec());
+    }
+
+    fn flash_offset(&self, address: usize, length: usize) -> Option<usize> {
+        let offset = address.checked_sub(SIM_FLASH_BASE)?;
+        if offset + length > self.flash.len() {
+            return None;
+        }
+        Some(offset)
+    }
+
+    // Pull as many complete frames (and their trailing raw data) out of the input as possible
+    fn process_input(&mut self) {
+        loop {
+            if let Some(trailing) = &self.trailing {
+                let needed = match trailing {
+                    Trailing::ProgrammerImage { length, .. } => *length,
+                    Trailing::BurnChunk { .. } => FLASH_BUFFER_SIZE,
+                };
+                if self.input.len() < needed {
+                    return;
+                }
+                let data: Vec<u8> = self.input.drain(..needed).collect();
+                let trailing = self.trailing.take().unwrap();
+                self.handle_trailing(trailing, &data);
+                continue;
+            }
+            // Resync on the next sync byte, like the real device would
+            match self.input.iter().position(|b| *b == BES_SYNC) {
+                Some(start) => {
+                    self.input.drain(..start);
+                }
+                None => {
+                    self.input.clear();
+                    return;
+                }
+            }
+            if self.input.len() < 4 {
+                return;
+            }
+            let frame_len = 5 + self.input[3] as usize;
+            if self.input.len() < frame_len {
+                return;
+            }
+            let frame: Vec<u8> = self.input.drain(..frame_len).collect();
+            if calculate_message_checksum(&frame[..frame_len - 1]) != frame[frame_len - 1] {
+                // Real device silently drops frames with a bad checksum
+                continue;
+            }
+            self.handle_frame(frame[1], frame[2], &frame[4..frame_len - 1]);
+        }
+    }
+
+    fn handle_frame(&mut self, type1: u8, seq: u8, data: &[u8]) {
+        match (self.state, MessageTypes::from(type1)) {
+            (SimState::Rom, MessageTypes::Sync) => {
+                let [low, high] = self.rom_version.to_le_bytes();
+                self.reply(MessageTypes::Sync, seq, &[0x02, low, high]);
+            }
+            (SimState::Rom, MessageTypes::StartProgrammer) => {
+                let length = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+                let crc = u32::from_le_bytes(data[8..12].try_into().unwrap());
+                self.programmer_announce = Some((length, crc));
+                self.reply(MessageTypes::StartProgrammer, seq, &[0x00]);
+            }
+            (SimState::Rom, MessageTypes::ProgrammerRunning) => {
+                if let Some((length, crc)) = self.programmer_announce {
+                    self.trailing = Some(Trailing::ProgrammerImage { length, crc });
+                }
+            }
+            (SimState::ProgrammerLoaded, MessageTypes::ProgrammerStart) => {
+                self.state = SimState::Programmer;
+                self.reply(MessageTypes::ProgrammerInit, 0x00, &PROGRAMMER_INIT_REPLY);
+            }
+            (SimState::Programmer, MessageTypes::FlashCommand) => {
+                self.handle_flash_command(seq, data)
+            }
+            (SimState::Programmer, MessageTypes::EraseBurnStart) => {
+                let address = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+                let length = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+                match self.flash_offset(address, length) {
+                    Some(offset) => {
+                        let erase_start = offset / self.sector_size * self.sector_size;
+                        let erase_end = (offset + length)
+                            .next_multiple_of(self.sector_size)
+                            .min(self.flash.len());
+                        self.flash[erase_start..erase_end].fill(0xFF);
+                        self.burn_window = Some((offset, length));
+                        self.reply(MessageTypes::EraseBurnStart, seq, &[0x00]);
+                    }
+                    None => self.reply(MessageTypes::EraseBurnStart, seq, &[0x01]),
+                }
+            }
+            (SimState::Programmer, MessageTypes::FlashBurnData) => {
+                let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
+                let chunk = u16::from_le_bytes(data[8..10].try_into().unwrap()) as usize;
+                self.trailing = Some(Trailing::BurnChunk { seq, chunk, crc });
+            }
+            (SimState::Rom | SimState::Programmer, MessageTypes::FlashRead) => {
+                let address = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+                let length = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+                let mut dump = match self.flash_offset(address, length) {
+                    Some(offset) => self.flash[offset..offset + length].to_vec(),
+                    None => vec![0x00; length],
+                };
+                if self.corrupt_reads > 0 {
+                    self.corrupt_reads -= 1;
+                    dump[length / 2] ^= 0x10;
+                }
+                self.reply(MessageTypes::FlashRead, seq, &[0x00]);
+                self.output.extend(dump);
+            }
+            (_, MessageTypes::DeviceCommand) if data == [0xF1] => {
+                self.reply(MessageTypes::DeviceCommand, seq, &[0x00]);
+                self.reboots += 1;
+                self.boot();
+            }
+            (SimState::Programmer, MessageTypes::DeviceCommand)
+                if data[0] == 0xF4 && self.accepts_baud_change =>
+            {
+                self.reply(MessageTypes::DeviceCommand, seq, &[0x00]);
+                self.pending_baud_rate = Some(u32::from_le_bytes(data[1..5].try_into().unwrap()));
+            }
+            (_, MessageTypes::DeviceCommand) => {
+                self.reply(MessageTypes::DeviceCommand, seq, &[0x01]);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_flash_command(&mut self, seq: u8, data: &[u8]) {
+        match data[0] {
+            0x11 => {
+                let mut reply = vec![0x00];
+                reply.extend(FLASH_ID);
+                self.reply(MessageTypes::FlashCommand, seq, &reply);
+            }
+            0x12 => {
+                let mut reply = vec![0x00];
+                reply.extend(FLASH_UNIQUE_ID);
+                self.reply(MessageTypes::FlashCommand, seq, &reply);
+            }
+            0x22 => {
+                let address = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+                let status = match self.burn_window {
+                    Some((offset, _))
+                        if data[5..9] == BURN_COMMIT_MAGIC
+                            && self.flash_offset(address, 0) == Some(offset) =>
+                    {
+                        0x00
+                    }
+                    _ => 0x01,
+                };
+                self.burn_window = None;
+                self.reply(MessageTypes::FlashCommand, seq, &[status]);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_trailing(&mut self, trailing: Trailing, data: &[u8]) {
+        let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        match trailing {
+            Trailing::ProgrammerImage { crc, .. } => {
+                if crc32.checksum(data) == crc {
+                    self.state = SimState::ProgrammerLoaded;
+                    self.reply(MessageTypes::ProgrammerRunning, 0xA2, &[0x20]);
+                } else {
+                    self.reply(MessageTypes::ProgrammerRunning, 0xA2, &[0x21]);
+                }
+            }
+            Trailing::BurnChunk { seq, chunk, crc } => {
+                let status = match self.burn_window {
+                    Some((offset, length))
+                        if crc32.checksum(data) == crc
+                            && (chunk + 1) * FLASH_BUFFER_SIZE <= length =>
+                    {
+                        let start = offset + chunk * FLASH_BUFFER_SIZE;
+                        self.flash[start..start + FLASH_BUFFER_SIZE].copy_from_slice(data);
+                        self.burned_chunks += 1;
+                        0x60
+                    }
+                    _ => 0xE0,
+                };
+                let [lo, hi] = (chunk as u16).to_le_bytes();
+                self.reply(MessageTypes::FlashBurnData, seq, &[status, lo, hi]);
+            }
+        }
+    }
+}
+
+impl Read for DeviceSimulator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Replies sent at a different rate never make it through as anything usable
+        if self.host_baud_rate != self.device_baud_rate {
+            self.output.clear();
+        }
+        if self.output.is_empty() {
+            return Err(std::io::Error::from(TimedOut));
+        }
+        let n = buf.len().min(self.output.len());
+        for (dest, src) in buf.iter_mut().zip(self.output.drain(..n)) {
+            *dest = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for DeviceSimulator {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(baud_rate) = self.pending_baud_rate.take() {
+            self.device_baud_rate = baud_rate;
+            self.output.clear();
+        }
+        if self.host_baud_rate != self.device_baud_rate {
+            return Ok(buf.len());
+        }
+        self.input.extend_from_slice(buf);
+        self.process_input();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BesTransport for DeviceSimulator {
+    fn set_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        self.host_baud_rate = baud_rate;
+        Ok(())
+    }
+
+    // Wired like a dtr-pulse fixture: the chip is held in reset while DTR is asserted
+    fn set_dtr(&mut self, level: bool) -> std::io::Result<()> {
+        if level {
+            self.output.clear();
+        } else {
+            self.boot();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::read_flash::read_flash_data;
+    use crate::beslink::simulator::{DeviceSimulator, SimState, SIM_FLASH_BASE};
+    use crate::beslink::{
+        burn_image_to_flash, find_chip, helper_sync_and_load_programmer, send_device_reboot,
+        BESLinkError, ProgrammingOptions, Timeouts, FLASH_BUFFER_SIZE,
+    };
+
+    const CHUNK1_TEST: &[u8; 32768] = include_bytes!("../../../chunk1.bin");
+    const CHUNK2_TEST: &[u8; 32768] = include_bytes!("../../../chunk2.bin");
+
+    #[test]
+    fn test_sync_and_load_programmer() {
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(
+            &mut sim,
+            &ProgrammingOptions::default(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        assert_eq!(sim.state, SimState::Programmer);
+    }
+    #[test]
+    fn test_forced_chip_loads_on_unknown_rom() {
+        let mut sim = DeviceSimulator::new();
+        sim.rom_version = 0x0999;
+        assert!(matches!(
+            helper_sync_and_load_programmer(
+                &mut sim,
+                &ProgrammingOptions::default(),
+                &Timeouts::default(),
+            ),
+            Err(BESLinkError::UnknownChip {
+                rom_version: 0x0999
+            })
+        ));
+        assert_eq!(sim.state, SimState::Rom);
+
+        let options = ProgrammingOptions {
+            chip: find_chip("BES2300"),
+            ..ProgrammingOptions::default()
+        };
+        let device_info =
+            helper_sync_and_load_programmer(&mut sim, &options, &Timeouts::default()).unwrap();
+        assert_eq!(device_info.rom_version, 0x0999);
+        assert_eq!(device_info.chip.name, "BES2300");
+        assert_eq!(sim.state, SimState::Programmer);
+    }
+    #[test]
+    fn test_write_then_read_round_trip() {
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(
+            &mut sim,
+            &ProgrammingOptions::default(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        let mut image = CHUNK1_TEST.to_vec();
+        image.extend_from_slice(CHUNK2_TEST);
+        image.extend_from_slice(&[0x5A; 1234]);
+        burn_image_to_flash(
+            &mut sim,
+            image.clone(),
+            SIM_FLASH_BASE + 0x10000,
+            &Timeouts::default(),
+        )
+        .unwrap();
+        assert_eq!(sim.burned_chunks, 3);
+
+        let (read_back, retried_chunks) = read_flash_data(
+            &mut sim,
+            SIM_FLASH_BASE + 0x10000,
+            image.len(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        assert_eq!(read_back, image);
+        assert_eq!(retried_chunks, 0);
+        // Image is padded out to a whole buffer with erased bytes
+        let pad_start = 0x10000 + image.len();
+        assert!(sim.flash[pad_start..0x10000 + 3 * FLASH_BUFFER_SIZE]
+            .iter()
+            .all(|b| *b == 0xFF));
+        assert!(sim.flash[..0x10000].iter().all(|b| *b == 0xFF));
+    }
+    #[test]
+    fn test_reboot_returns_to_rom() {
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(
+            &mut sim,
+            &ProgrammingOptions::default(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        send_device_reboot(&mut sim, &Timeouts::default()).unwrap();
+        assert_eq!(sim.state, SimState::Rom);
+        assert_eq!(sim.reboots, 1);
+        // And it can be brought back up again after the reboot
+        helper_sync_and_load_programmer(
+            &mut sim,
+            &ProgrammingOptions::default(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        assert_eq!(sim.state, SimState::Programmer);
+    }
+    #[test]
+    fn test_read_retries_corrupted_chunk() {
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(
+            &mut sim,
+            &ProgrammingOptions::default(),
+            &Timeouts::default(),
+        )
+        .unwrap();
+        sim.flash[..0x8000].fill(0x5A);
+        // The first read of the first chunk is corrupted, so it takes three reads to get two that agree
+        sim.corrupt_reads = 1;
+        let (read_back, retried_chunks) =
+            read_flash_data(&mut sim, SIM_FLASH_BASE, 0x8000, &Timeouts::default()).unwrap();
+        assert!(read_back.iter().all(|b| *b == 0x5A));
+        assert_eq!(retried_chunks, 1);
+    }
+}
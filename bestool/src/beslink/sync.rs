@@ -1,23 +1,23 @@
 use crate::beslink::errors::BESLinkError;
 use crate::beslink::message::read_message;
 use crate::beslink::message::MessageTypes;
-use crate::beslink::BesMessage;
-use serialport::SerialPort;
+use crate::beslink::{BesMessage, BesTransport, Deadline};
 use tracing::{debug, warn};
 pub fn sync(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     sync_type: MessageTypes,
+    deadline: &Deadline,
 ) -> Result<BesMessage, BESLinkError> {
     debug!("Finding Sync on the port for type {:?}", sync_type);
     loop {
-        match read_message(serial_port) {
+        match read_message(transport, deadline) {
             Ok(packet) => {
-                if packet.type1 == sync_type {
+                if packet.type1() == sync_type {
                     return Ok(packet);
                 } else {
                     warn!(
                         "Ignored packet type {:?} waiting for {:?} => {:X?}",
-                        packet.type1,
+                        packet.type1(),
                         sync_type,
                         packet.to_vec()
                     );
@@ -27,9 +27,12 @@ pub fn sync(
                 BESLinkError::BadChecksumError { .. } => {
                     warn!("Ignoring bad checksum; you might not be in programmer mode.")
                 }
+                // Line noise, same as a bad checksum
+                BESLinkError::BadFrameLength { .. } => {
+                    warn!("Ignoring frame with a bad length; {}", e)
+                }
                 _ => return Err(e),
             },
         }
     }
-    // return Err(BESLinkError::Timeout);
 }
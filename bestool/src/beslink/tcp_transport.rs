@@ -0,0 +1,67 @@
+use crate::beslink::BesTransport;
+use std::io::ErrorKind::{TimedOut, WouldBlock};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tracing::debug;
+
+// Raw byte bridge to a uart on another machine (e.g. ser2net in raw mode on a bench PC)
+// The far end owns the actual uart settings, so baud changes are only recorded locally
+pub struct TcpTransport {
+    stream: TcpStream,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+impl TcpTransport {
+    pub fn connect(address: &str, baud_rate: u32) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let timeout = Duration::from_millis(1000);
+        stream.set_read_timeout(Some(timeout))?;
+        Ok(TcpTransport {
+            stream,
+            timeout,
+            baud_rate,
+        })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.stream.read(buf) {
+            // Some platforms report an expired read timeout as WouldBlock; the protocol code expects TimedOut like a uart
+            Err(e) if e.kind() == WouldBlock => Err(std::io::Error::from(TimedOut)),
+            Ok(0) if !buf.is_empty() => Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe)),
+            r => r,
+        }
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl BesTransport for TcpTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        debug!("Baud rate change to {baud_rate} is handled by the remote end of the socket");
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        false
+    }
+}
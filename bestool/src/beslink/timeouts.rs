@@ -0,0 +1,100 @@
+use crate::beslink::BESLinkError;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Each part of a session gets its own deadline, so a device that stops answering fails the command
+// with the stage that stalled instead of leaving bestool waiting forever
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stage {
+    Open,     // A gang write port showing up with --wait
+    Sync,     // Catching the ROM, loading and starting the programmer
+    Command,  // Short request/response commands (flash ID, reboot)
+    Erase,    // Erase/burn start
+    ChunkAck, // Ack for each burnt flash chunk
+    Commit,   // Final burn commit
+    Read,     // Flash read back
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Open => "port open",
+            Stage::Sync => "sync",
+            Stage::Command => "command",
+            Stage::Erase => "erase",
+            Stage::ChunkAck => "chunk ack",
+            Stage::Commit => "commit",
+            Stage::Read => "read",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Timeouts {
+    pub open: Duration,
+    pub sync: Duration,
+    pub command: Duration,
+    pub erase: Duration,
+    pub chunk_ack: Duration,
+    pub commit: Duration,
+    pub read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            // Long enough to plug in a bench full of devices
+            open: Duration::from_secs(60),
+            // Long enough for someone to power cycle the board after starting the command
+            sync: Duration::from_secs(60),
+            command: Duration::from_secs(5),
+            // Erasing a whole 4MiB part can take a while
+            erase: Duration::from_secs(60),
+            chunk_ack: Duration::from_secs(10),
+            commit: Duration::from_secs(10),
+            read: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn deadline(&self, stage: Stage) -> Deadline {
+        let timeout = match stage {
+            Stage::Open => self.open,
+            Stage::Sync => self.sync,
+            Stage::Command => self.command,
+            Stage::Erase => self.erase,
+            Stage::ChunkAck => self.chunk_ack,
+            Stage::Commit => self.commit,
+            Stage::Read => self.read,
+        };
+        Deadline {
+            stage,
+            at: Instant::now() + timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    stage: Stage,
+    at: Instant,
+}
+
+impl Deadline {
+    // A shorter wait inside this one, failing as the same stage
+    pub fn at_most(&self, timeout: Duration) -> Deadline {
+        Deadline {
+            stage: self.stage,
+            at: self.at.min(Instant::now() + timeout),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), BESLinkError> {
+        if Instant::now() >= self.at {
+            return Err(BESLinkError::Timeout { stage: self.stage });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use serialport::SerialPort;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// The protocol layer only needs a byte stream with a read timeout and the ability to change speed
+// Keeping it behind this trait means the same flashing code can run over a real uart, a socket to a
+// bench machine, or a buffer in tests
+pub trait BesTransport: Read + Write + Send {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()>;
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()>;
+    // Whether set_baud_rate really changes the line speed, rather than it being fixed at the far end
+    fn can_change_baud_rate(&self) -> bool {
+        true
+    }
+    // Drop anything sitting in the input/output buffers; not every transport has any
+    fn clear_buffers(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+    // Uart control lines, used to reset boards wired for it
+    fn set_dtr(&mut self, _level: bool) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "this transport has no DTR line",
+        ))
+    }
+    fn set_rts(&mut self, _level: bool) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "this transport has no RTS line",
+        ))
+    }
+}
+
+impl BesTransport for dyn SerialPort {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        Ok(SerialPort::set_timeout(self, timeout)?)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        Ok(SerialPort::set_baud_rate(self, baud_rate)?)
+    }
+
+    fn clear_buffers(&mut self) -> std::io::Result<()> {
+        Ok(self.clear(serialport::ClearBuffer::All)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> std::io::Result<()> {
+        Ok(self.write_data_terminal_ready(level)?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> std::io::Result<()> {
+        Ok(self.write_request_to_send(level)?)
+    }
+}
+
+impl<T: BesTransport + ?Sized> BesTransport for Box<T> {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        (**self).set_baud_rate(baud_rate)
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        (**self).can_change_baud_rate()
+    }
+
+    fn clear_buffers(&mut self) -> std::io::Result<()> {
+        (**self).clear_buffers()
+    }
+
+    fn set_dtr(&mut self, level: bool) -> std::io::Result<()> {
+        (**self).set_dtr(level)
+    }
+
+    fn set_rts(&mut self, level: bool) -> std::io::Result<()> {
+        (**self).set_rts(level)
+    }
+}
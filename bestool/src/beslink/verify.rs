@@ -0,0 +1,96 @@
+use crate::beslink::{
+    read_flash_in_segments, BESLinkError, BesTransport, ProgrammingOptions, Timeouts,
+};
+use std::ops::Range;
+use tracing::info;
+
+// Reads back what was just burnt and compares it against the image, segment by segment.
+// Expects the programmer to be running, as it is straight after a burn
+pub fn verify_flash(
+    transport: &mut dyn BesTransport,
+    address: usize,
+    expected: &[u8],
+    options: &ProgrammingOptions,
+    timeouts: &Timeouts,
+) -> Result<(), BESLinkError> {
+    let mut mismatches = vec![];
+    read_flash_in_segments(
+        transport,
+        address,
+        expected.len(),
+        options,
+        timeouts,
+        |pos, segment| {
+            let offset = pos - address;
+            mismatches.extend(find_mismatches(
+                pos,
+                &expected[offset..offset + segment.len()],
+                &segment,
+            ));
+            Ok(())
+        },
+    )?;
+    if !mismatches.is_empty() {
+        return Err(BESLinkError::VerifyFailed { mismatches });
+    }
+    info!("Verified 0x{:X} bytes at 0x{:08X}", expected.len(), address);
+    Ok(())
+}
+
+// Address ranges (starting at address) where the two buffers differ
+fn find_mismatches(address: usize, expected: &[u8], actual: &[u8]) -> Vec<Range<usize>> {
+    let mut mismatches: Vec<Range<usize>> = vec![];
+    for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+        if e == a {
+            continue;
+        }
+        let pos = address + i;
+        match mismatches.last_mut() {
+            Some(last) if last.end == pos => last.end = pos + 1,
+            _ => mismatches.push(pos..pos + 1),
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::simulator::{DeviceSimulator, SIM_FLASH_BASE};
+    use crate::beslink::verify::{find_mismatches, verify_flash};
+    use crate::beslink::{
+        burn_image_to_flash, helper_sync_and_load_programmer, BESLinkError, ProgrammingOptions,
+        Timeouts,
+    };
+
+    #[test]
+    fn test_find_mismatches_merges_runs() {
+        let expected = [0, 1, 2, 3, 4, 5, 6, 7];
+        let actual = [0, 9, 9, 3, 4, 5, 6, 9];
+        assert_eq!(
+            find_mismatches(0x100, &expected, &actual),
+            vec![0x101..0x103, 0x107..0x108]
+        );
+        assert!(find_mismatches(0, &expected, &expected).is_empty());
+    }
+    #[test]
+    fn test_verify_flash_reports_corruption() {
+        let timeouts = Timeouts::default();
+        let options = ProgrammingOptions::default();
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &options, &timeouts).unwrap();
+        let image: Vec<u8> = (0..0x9000).map(|i| (i * 7) as u8).collect();
+        burn_image_to_flash(&mut sim, image.clone(), SIM_FLASH_BASE, &timeouts).unwrap();
+        verify_flash(&mut sim, SIM_FLASH_BASE, &image, &options, &timeouts).unwrap();
+
+        sim.flash[0x1234] ^= 0xFF;
+        match verify_flash(&mut sim, SIM_FLASH_BASE, &image, &options, &timeouts) {
+            Err(BESLinkError::VerifyFailed { mismatches }) => {
+                assert_eq!(
+                    mismatches,
+                    vec![SIM_FLASH_BASE + 0x1234..SIM_FLASH_BASE + 0x1235]
+                );
+            }
+            other => panic!("Expected a verify failure, got {other:?}"),
+        }
+    }
+}
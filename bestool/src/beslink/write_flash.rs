@@ -1,18 +1,56 @@
 use crate::beslink::{
-    send_message, sync, BESLinkError, BesMessage, MessageTypes, BES_SYNC, FLASH_BUFFER_SIZE,
+    check_status, read_flash_in_segments, send_message, sync, BESLinkError, BesMessage,
+    BesTransport, BurnAck, EraseBurnStart, FlashBurnCommit, MessageTypes, ProgrammingOptions,
+    Stage, Timeouts, BURN_ACK_OK, FLASH_BUFFER_SIZE,
 };
 use crc::{Crc, CRC_32_ISO_HDLC};
-use serialport::SerialPort;
-use std::io::Write;
 use std::time::Duration;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 const MAX_UNACKED_PACKETS: usize = 2;
 
+// Burns have to start on a sector boundary of the flash part, inside the flash
+pub fn check_burn_address(
+    address: usize,
+    flash_base: usize,
+    sector_size: usize,
+) -> Result<(), BESLinkError> {
+    match address.checked_sub(flash_base) {
+        Some(offset) if sector_size > 0 && offset.is_multiple_of(sector_size) => Ok(()),
+        _ => Err(BESLinkError::BadFlashAddress {
+            address,
+            sector_size,
+        }),
+    }
+}
+
+// The flash size comes from the ID; parts we cannot size are let through rather than refused
+pub fn check_flash_range(
+    address: usize,
+    length: usize,
+    flash_base: usize,
+    flash_size: Option<usize>,
+) -> Result<(), BESLinkError> {
+    let Some(flash_size) = flash_size else {
+        warn!("Flash size is unknown, not checking 0x{length:X} bytes at 0x{address:08X} fit");
+        return Ok(());
+    };
+    match address.checked_sub(flash_base) {
+        Some(offset) if offset + length <= flash_size => Ok(()),
+        _ => Err(BESLinkError::PastEndOfFlash {
+            address,
+            length,
+            flash_size,
+        }),
+    }
+}
+
 pub fn burn_image_to_flash(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     payload_in: Vec<u8>,
     address: usize,
+    timeouts: &Timeouts,
 ) -> Result<(), BESLinkError> {
     let mut payload = payload_in;
     //Pad image to FLASH_BUFFER_SIZE
@@ -20,7 +58,7 @@ pub fn burn_image_to_flash(
         payload.push(0xFF);
     }
     let file_length = payload.len();
-    match send_flash_erase(serial_port, file_length, address) {
+    match send_flash_erase(transport, file_length, address, timeouts) {
         Ok(m) => {
             info!("Flash Erase confirmed, {:?}", m)
         }
@@ -42,7 +80,7 @@ pub fn burn_image_to_flash(
                     "Sending flash chunk {} out of {}",
                     chunk_num, file_chunk_count
                 );
-                send_flash_chunk_msg(serial_port, chunk.to_vec(), chunk_num)?;
+                send_flash_chunk_msg(transport, chunk.to_vec(), chunk_num)?;
                 if chunk_num == 0x00 {
                     std::thread::sleep(Duration::from_millis(411));
                 }
@@ -51,86 +89,205 @@ pub fn burn_image_to_flash(
                 break; // Step to next chunk
             }
             //Wait for an ack
-            match sync(serial_port, MessageTypes::FlashBurnData) {
-                Ok(m) => {
-                    outstanding_chunks -= 1;
-                    info!("Confirmation for message {}", m.payload[3]);
-                }
-                Err(e) => {
-                    error!("Waiting for flash confirmation, {}", e);
-                }
-            }
+            wait_for_chunk_ack(transport, timeouts)?;
+            outstanding_chunks -= 1;
         }
     }
     //Wait for rest of chunk confirmations
     while outstanding_chunks > 0 {
-        match sync(serial_port, MessageTypes::FlashBurnData) {
+        wait_for_chunk_ack(transport, timeouts)?;
+        outstanding_chunks -= 1;
+    }
+    info!("Sending flash finalise");
+    send_flash_commit_message(transport, address, timeouts)
+}
+
+// There is no known erase-only command, so a range is erased by going through the normal
+// erase/burn with blank data. The burn is padded out to whole flash buffers and the programmer may erase
+// whole sectors, so the range has to start and end on a boundary of both, or run to the end of the flash
+pub fn erase_flash(
+    transport: &mut dyn BesTransport,
+    address: usize,
+    length: usize,
+    flash_base: usize,
+    flash_size: Option<usize>,
+    sector_size: usize,
+    timeouts: &Timeouts,
+) -> Result<(), BESLinkError> {
+    let block_size = erase_block_size(sector_size);
+    check_burn_address(address, flash_base, block_size)?;
+    let runs_to_end = flash_size == Some(address - flash_base + length);
+    if length == 0
+        || !(length.is_multiple_of(block_size)
+            || runs_to_end && length.is_multiple_of(FLASH_BUFFER_SIZE))
+    {
+        return Err(BESLinkError::BadFlashLength { length, block_size });
+    }
+    burn_image_to_flash(transport, vec![0xFF; length], address, timeouts)
+}
+
+// Smallest unit that is both whole sectors and whole flash buffers
+fn erase_block_size(sector_size: usize) -> usize {
+    let (mut a, mut b) = (sector_size.max(1), FLASH_BUFFER_SIZE);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    sector_size.max(1) / a * FLASH_BUFFER_SIZE
+}
+
+// Reads what is in flash already and only erases and burns the sectors that differ, runs of changed sectors
+// are burnt together. Returns the number of bytes that were left alone.
+// The address has to be on a sector boundary. Burns are padded out to whole flash buffers, and the programmer
+// may erase whole sectors around a burn, so each run is grown with the image's own (unchanged) data until it
+// ends on both a sector and a buffer boundary, or at the end of the image
+pub fn burn_image_to_flash_incremental(
+    transport: &mut dyn BesTransport,
+    payload_in: Vec<u8>,
+    address: usize,
+    sector_size: usize,
+    options: &ProgrammingOptions,
+    timeouts: &Timeouts,
+) -> Result<usize, BESLinkError> {
+    let mut payload = payload_in;
+    while !payload.len().is_multiple_of(FLASH_BUFFER_SIZE) {
+        payload.push(0xFF);
+    }
+    let sector_size = sector_size.max(1);
+    // The programmer has no way we know of to compare flash itself, so read it all back
+    let mut current = Vec::with_capacity(payload.len());
+    read_flash_in_segments(
+        transport,
+        address,
+        payload.len(),
+        options,
+        timeouts,
+        |_, segment| {
+            current.extend(segment);
+            Ok(())
+        },
+    )?;
+    if current.len() != payload.len() {
+        return Err(BESLinkError::ShortRead {
+            address,
+            got: current.len(),
+            wanted: payload.len(),
+        });
+    }
+    let sector_changed = |sector: usize| {
+        let range = sector * sector_size..((sector + 1) * sector_size).min(payload.len());
+        payload[range.clone()] != current[range]
+    };
+
+    let sectors = payload.len().div_ceil(sector_size);
+    let mut burnt = 0;
+    let mut sector = 0;
+    while sector < sectors {
+        if !sector_changed(sector) {
+            sector += 1;
+            continue;
+        }
+        let start = sector * sector_size;
+        while sector < sectors && sector_changed(sector) {
+            sector += 1;
+        }
+        let mut end = (sector * sector_size).min(payload.len());
+        loop {
+            let padded_end = start + (end - start).next_multiple_of(FLASH_BUFFER_SIZE);
+            if padded_end >= payload.len() {
+                end = payload.len();
+                break;
+            }
+            if padded_end.is_multiple_of(sector_size) {
+                end = padded_end;
+                break;
+            }
+            end = padded_end.next_multiple_of(sector_size).min(payload.len());
+        }
+        sector = end.div_ceil(sector_size);
+        info!(
+            "Burning changed sectors from 0x{:08X} to 0x{:08X}",
+            address + start,
+            address + end
+        );
+        burn_image_to_flash(
+            transport,
+            payload[start..end].to_vec(),
+            address + start,
+            timeouts,
+        )?;
+        burnt += end - start;
+    }
+    Ok(payload.len() - burnt)
+}
+fn wait_for_chunk_ack(
+    transport: &mut dyn BesTransport,
+    timeouts: &Timeouts,
+) -> Result<(), BESLinkError> {
+    // Garbled acks are retried, but only until the deadline for this ack runs out
+    let deadline = timeouts.deadline(Stage::ChunkAck);
+    loop {
+        match sync(transport, MessageTypes::FlashBurnData, &deadline) {
             Ok(m) => {
-                outstanding_chunks -= 1;
-                info!("Confirmation for message {}", m.payload[3]);
+                let ack = BurnAck::from_message(&m)?;
+                if ack.status != BURN_ACK_OK {
+                    return Err(BESLinkError::BadResponseCode {
+                        failed_packet: m.to_vec(),
+                        got: ack.status,
+                        wanted: BURN_ACK_OK,
+                    });
+                }
+                info!(
+                    "Confirmation for message {} (seq 0x{:02X})",
+                    ack.block, ack.seq
+                );
+                return Ok(());
+            }
+            Err(BESLinkError::Timeout { stage }) => {
+                return Err(BESLinkError::Timeout { stage });
             }
             Err(e) => {
                 error!("Waiting for flash confirmation, {}", e);
             }
         }
     }
-    info!("Sending flash finalise");
-    send_flash_commit_message(serial_port, address)
 }
 fn send_flash_commit_message(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     address: usize,
+    timeouts: &Timeouts,
 ) -> Result<(), BESLinkError> {
-    let mut burn_prepare_message = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::FlashCommand,
-        payload: vec![0x06, 0x09, 0x22],
-        checksum: 0xEB,
+    let commit = FlashBurnCommit {
+        addr: address as u32,
     };
-    burn_prepare_message
-        .payload
-        .extend((address as u32).to_le_bytes());
-
-    burn_prepare_message
-        .payload
-        .extend(vec![0x1C, 0xEC, 0x57, 0xBE]);
-    burn_prepare_message.set_checksum();
-    send_message(serial_port, burn_prepare_message)?;
+    send_message(transport, commit.to_message())?;
     info!("Sent flash finalise message");
-    let resp = sync(serial_port, MessageTypes::FlashCommand)?;
-    if resp.payload != vec![6, 1, 0] {
-        return Err(BESLinkError::BadResponseCode {
-            failed_packet: resp.to_vec(),
-            got: resp.payload[0],
-            wanted: 0x06,
-        });
-    }
-    Ok(())
+    let resp = sync(
+        transport,
+        MessageTypes::FlashCommand,
+        &timeouts.deadline(Stage::Commit),
+    )?;
+    check_status(&resp, 0x00)
 }
-fn get_flash_chunk_msg(payload: Vec<u8>, chunk: usize) -> BesMessage {
-    let mut data_message = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::FlashBurnData,
-        payload: vec![0xC1 + (chunk as u8), 0x0B],
-        checksum: 0xEB,
-    };
-    data_message
-        .payload
-        .extend((FLASH_BUFFER_SIZE as u16).to_le_bytes());
-    data_message.payload.extend(vec![0x00, 0x00]);
-
+fn chunk_crc32(chunk: &[u8]) -> u32 {
     let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
     let mut digest = crc.digest();
-    digest.update(&payload);
-    let crc_value = digest.finalize();
-    data_message.payload.extend(crc_value.to_le_bytes());
-    data_message.payload.extend(vec![chunk as u8, 0x00, 0x00]);
-    data_message.set_checksum();
-    data_message
+    digest.update(chunk);
+    digest.finalize()
+}
+fn get_flash_chunk_msg(payload: Vec<u8>, chunk: usize) -> BesMessage {
+    let mut header = vec![];
+    header.extend((FLASH_BUFFER_SIZE as u32).to_le_bytes());
+    header.extend(chunk_crc32(&payload).to_le_bytes());
+    header.extend(vec![chunk as u8, 0x00, 0x00]);
+    BesMessage::new(
+        MessageTypes::FlashBurnData,
+        0xC1_u8.wrapping_add(chunk as u8),
+        header,
+    )
 }
 
 fn send_flash_chunk_msg(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     payload: Vec<u8>,
     chunk: usize,
 ) -> Result<(), BESLinkError> {
@@ -141,7 +298,7 @@ fn send_flash_chunk_msg(
     let mut message_vec = data_message.to_vec();
     message_vec.extend(payload);
 
-    match serial_port.write_all(message_vec.as_slice()) {
+    match transport.write_all(message_vec.as_slice()) {
         Ok(_) => {
             info!("Wrote flash buffer of len 0x{:X} ", message_vec.len());
             std::thread::sleep(Duration::from_millis(10)); // This is just a small rate limiter
@@ -155,45 +312,41 @@ fn send_flash_chunk_msg(
 }
 
 fn send_flash_erase(
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     payload_len: usize,
     address: usize,
+    timeouts: &Timeouts,
 ) -> Result<BesMessage, BESLinkError> {
-    let mut burn_prepare_message = BesMessage {
-        sync: BES_SYNC,
-        type1: MessageTypes::EraseBurnStart,
-        payload: vec![0x05, 0x0C],
-        checksum: 0xEB,
-    };
-    burn_prepare_message
-        .payload
-        .extend((address as u32).to_le_bytes());
-    burn_prepare_message
-        .payload
-        .extend((payload_len as u32).to_le_bytes());
-    burn_prepare_message
-        .payload
-        .extend(vec![0x00, 0x80, 0x00, 0x00]);
-    burn_prepare_message.set_checksum();
+    let burn_prepare_message = EraseBurnStart {
+        addr: address as u32,
+        len: payload_len as u32,
+    }
+    .to_message();
     info!(
         "Sent erase start message, {:X?}",
         burn_prepare_message.to_vec()
     );
-    send_message(serial_port, burn_prepare_message)?;
-    let resp = sync(serial_port, MessageTypes::EraseBurnStart)?;
-    if resp.payload != vec![0x05, 0x01, 0x00] {
-        return Err(BESLinkError::BadResponseCode {
-            failed_packet: resp.to_vec(),
-            got: resp.payload[0],
-            wanted: 0x05,
-        });
-    }
+    send_message(transport, burn_prepare_message)?;
+    let resp = sync(
+        transport,
+        MessageTypes::EraseBurnStart,
+        &timeouts.deadline(Stage::Erase),
+    )?;
+    check_status(&resp, 0x00)?;
     Ok(resp)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::beslink::write_flash::get_flash_chunk_msg;
+    use crate::beslink::simulator::{DeviceSimulator, SIM_FLASH_SIZE};
+    use crate::beslink::write_flash::{
+        burn_image_to_flash_incremental, check_burn_address, check_flash_range, erase_block_size,
+        erase_flash, get_flash_chunk_msg,
+    };
+    use crate::beslink::{
+        burn_image_to_flash, helper_sync_and_load_programmer, BESLinkError, ProgrammingOptions,
+        Timeouts, BES_FLASH_BASE, FLASH_BUFFER_SIZE,
+    };
 
     //Embed the bin file for future
     const CHUNK1_TEST: &[u8; 32768] = include_bytes!("../../../chunk1.bin");
@@ -221,4 +374,213 @@ mod tests {
         let message_flat = message.to_vec();
         assert_eq!(message_flat, expected_header_data);
     }
+    #[test]
+    fn test_check_burn_address() {
+        assert!(check_burn_address(BES_FLASH_BASE, BES_FLASH_BASE, 0x1000).is_ok());
+        assert!(check_burn_address(BES_FLASH_BASE + 0x10000, BES_FLASH_BASE, 0x1000).is_ok());
+        assert!(check_burn_address(BES_FLASH_BASE + 0x12000, BES_FLASH_BASE, 0x1000).is_ok());
+        assert!(check_burn_address(BES_FLASH_BASE + 0x12800, BES_FLASH_BASE, 0x1000).is_err());
+        // Parts missing from the JEDEC table are held to 64KiB
+        assert!(check_burn_address(BES_FLASH_BASE + 0x12000, BES_FLASH_BASE, 0x1_0000).is_err());
+        // Below the start of flash
+        assert!(check_burn_address(0x2000_0000, BES_FLASH_BASE, 0x1000).is_err());
+    }
+    #[test]
+    fn test_check_flash_range() {
+        let size = Some(0x40_0000);
+        assert!(check_flash_range(BES_FLASH_BASE, 0x40_0000, BES_FLASH_BASE, size).is_ok());
+        assert!(
+            check_flash_range(BES_FLASH_BASE + 0x3F_8000, 0x8000, BES_FLASH_BASE, size).is_ok()
+        );
+        assert!(matches!(
+            check_flash_range(BES_FLASH_BASE + 0x3F_8000, 0x10000, BES_FLASH_BASE, size),
+            Err(BESLinkError::PastEndOfFlash { .. })
+        ));
+        // Unknown parts are not checked
+        assert!(
+            check_flash_range(BES_FLASH_BASE + 0x3F_8000, 0x10000, BES_FLASH_BASE, None).is_ok()
+        );
+    }
+    #[test]
+    fn test_incremental_burns_only_changed_sectors() {
+        let timeouts = Timeouts::default();
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        // 18 flash buffers
+        let mut image = vec![0x11; 0x9_0000];
+        burn_image_to_flash(&mut sim, image.clone(), BES_FLASH_BASE, &timeouts).unwrap();
+        assert_eq!(sim.burned_chunks, 18);
+
+        // One 4KiB sector changes, which is burnt as the buffer around it
+        image[0x5_0005] = 0x22;
+        let skipped = burn_image_to_flash_incremental(
+            &mut sim,
+            image.clone(),
+            BES_FLASH_BASE,
+            0x1000,
+            &ProgrammingOptions::default(),
+            &timeouts,
+        )
+        .unwrap();
+        assert_eq!(skipped, 0x8_8000);
+        assert_eq!(sim.burned_chunks, 18 + 1);
+        assert_eq!(sim.flash[..image.len()], image[..]);
+
+        // With 64KiB sectors the whole sector is burnt, two buffers
+        image[0x7_8005] = 0x33;
+        let skipped = burn_image_to_flash_incremental(
+            &mut sim,
+            image.clone(),
+            BES_FLASH_BASE,
+            0x1_0000,
+            &ProgrammingOptions::default(),
+            &timeouts,
+        )
+        .unwrap();
+        assert_eq!(skipped, 0x8_0000);
+        assert_eq!(sim.burned_chunks, 18 + 1 + 2);
+        assert_eq!(sim.flash[..image.len()], image[..]);
+
+        // Nothing to do the second time round
+        let skipped = burn_image_to_flash_incremental(
+            &mut sim,
+            image.clone(),
+            BES_FLASH_BASE,
+            0x1000,
+            &ProgrammingOptions::default(),
+            &timeouts,
+        )
+        .unwrap();
+        assert_eq!(skipped, image.len());
+        assert_eq!(sim.burned_chunks, 18 + 1 + 2);
+    }
+    #[test]
+    fn test_erase_flash_keeps_to_whole_sectors() {
+        let timeouts = Timeouts::default();
+        let mut sim = DeviceSimulator::new();
+        sim.sector_size = 0x1_0000;
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        let image = vec![0x11; 0x3_0000];
+        burn_image_to_flash(&mut sim, image.clone(), BES_FLASH_BASE, &timeouts).unwrap();
+
+        // Half a sector would take the other half with it, so it is refused before anything is sent
+        let start = BES_FLASH_BASE + 0x1_0000;
+        assert!(matches!(
+            erase_flash(
+                &mut sim,
+                start,
+                0x8000,
+                BES_FLASH_BASE,
+                Some(SIM_FLASH_SIZE),
+                0x1_0000,
+                &timeouts
+            ),
+            Err(BESLinkError::BadFlashLength {
+                length: 0x8000,
+                block_size: 0x1_0000
+            })
+        ));
+        assert_eq!(sim.flash[..image.len()], image[..]);
+
+        erase_flash(
+            &mut sim,
+            start,
+            0x1_0000,
+            BES_FLASH_BASE,
+            Some(SIM_FLASH_SIZE),
+            0x1_0000,
+            &timeouts,
+        )
+        .unwrap();
+        assert!(sim.flash[..0x1_0000].iter().all(|b| *b == 0x11));
+        assert!(sim.flash[0x1_0000..0x2_0000].iter().all(|b| *b == 0xFF));
+        assert!(sim.flash[0x2_0000..0x3_0000].iter().all(|b| *b == 0x11));
+
+        // What the check is there to stop: a bare half-sector burn loses the rest of the sector
+        burn_image_to_flash(&mut sim, vec![0x22; 0x8000], start + 0x1_0000, &timeouts).unwrap();
+        assert!(sim.flash[0x2_8000..0x3_0000].iter().all(|b| *b == 0xFF));
+    }
+    #[test]
+    fn test_erase_flash_blanks_only_the_range() {
+        let timeouts = Timeouts::default();
+        let mut sim = DeviceSimulator::new();
+        helper_sync_and_load_programmer(&mut sim, &ProgrammingOptions::default(), &timeouts)
+            .unwrap();
+        let image = vec![0x11; 3 * FLASH_BUFFER_SIZE];
+        burn_image_to_flash(&mut sim, image, BES_FLASH_BASE, &timeouts).unwrap();
+
+        let start = BES_FLASH_BASE + FLASH_BUFFER_SIZE;
+        erase_flash(
+            &mut sim,
+            start,
+            FLASH_BUFFER_SIZE,
+            BES_FLASH_BASE,
+            None,
+            0x8000,
+            &timeouts,
+        )
+        .unwrap();
+        assert!(sim.flash[..FLASH_BUFFER_SIZE].iter().all(|b| *b == 0x11));
+        assert!(sim.flash[FLASH_BUFFER_SIZE..2 * FLASH_BUFFER_SIZE]
+            .iter()
+            .all(|b| *b == 0xFF));
+        assert!(sim.flash[2 * FLASH_BUFFER_SIZE..3 * FLASH_BUFFER_SIZE]
+            .iter()
+            .all(|b| *b == 0x11));
+
+        assert!(matches!(
+            erase_flash(
+                &mut sim,
+                start,
+                0x1000,
+                BES_FLASH_BASE,
+                None,
+                0x8000,
+                &timeouts
+            ),
+            Err(BESLinkError::BadFlashLength { length: 0x1000, .. })
+        ));
+        assert!(matches!(
+            erase_flash(
+                &mut sim,
+                start + 0x1000,
+                0x8000,
+                BES_FLASH_BASE,
+                None,
+                0x8000,
+                &timeouts
+            ),
+            Err(BESLinkError::BadFlashAddress { .. })
+        ));
+
+        // 4KiB sectors fit in a buffer; a part with 64KiB sectors has to be erased in whole sectors
+        assert_eq!(erase_block_size(0x1000), FLASH_BUFFER_SIZE);
+        assert_eq!(erase_block_size(0x1_0000), 0x1_0000);
+        let size = Some(SIM_FLASH_SIZE);
+        assert!(matches!(
+            erase_flash(
+                &mut sim,
+                BES_FLASH_BASE,
+                0x8000,
+                BES_FLASH_BASE,
+                size,
+                0x1_0000,
+                &timeouts
+            ),
+            Err(BESLinkError::BadFlashLength { .. })
+        ));
+        let start = BES_FLASH_BASE + 0x3F_0000;
+        erase_flash(
+            &mut sim,
+            start,
+            0x1_0000,
+            BES_FLASH_BASE,
+            size,
+            0x1_0000,
+            &timeouts,
+        )
+        .unwrap();
+    }
 }
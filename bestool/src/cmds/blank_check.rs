@@ -0,0 +1,87 @@
+use super::read_image::flash_region_length;
+use super::Session;
+use crate::beslink::{
+    helper_sync_and_load_programmer, read_flash_in_segments, reboot_into_app, BESLinkError,
+    BesTransport, BlankChecker, NonBlankRange,
+};
+use crate::serial_port_opener::open_transport_with_wait;
+use std::ops::Range;
+use std::time::Duration;
+use tracing::error;
+use tracing::info;
+
+pub fn cmd_blank_check(
+    port_name: &str,
+    start: usize,
+    length: Option<usize>,
+    wait_for_port: bool,
+    session: &Session,
+) {
+    let baud_rate = session.programming.programming_baud_rate();
+    println!("Blank checking flash on {port_name} @ {baud_rate}");
+    let mut port =
+        open_transport_with_wait(port_name, baud_rate, wait_for_port, &session.transport);
+    port.set_timeout(Duration::from_millis(5000))
+        .expect("Cant set port timeout");
+
+    // Scripts rely on the exit code, so anything but a clean blank region is a failure
+    match do_blank_check(&mut port, start, length, session) {
+        Ok((0, region)) => {
+            println!("0x{:08X}..0x{:08X} is blank", region.start, region.end);
+        }
+        Ok((count, _)) => {
+            println!("Found {count} non-blank ranges");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Failed {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Returns how many non-blank ranges were found, each printed as soon as it is known, and the region checked
+fn do_blank_check(
+    transport: &mut dyn BesTransport,
+    start: usize,
+    length: Option<usize>,
+    session: &Session,
+) -> Result<(usize, Range<usize>), BESLinkError> {
+    info!("Starting loader and checking communications");
+    let device_info =
+        helper_sync_and_load_programmer(transport, &session.programming, &session.timeouts)?;
+    let length = flash_region_length(&device_info, start, length)?;
+    let address = device_info.chip.flash_base + start;
+    let mut checker = BlankChecker::new();
+    let mut count = 0;
+    read_flash_in_segments(
+        transport,
+        address,
+        length,
+        &session.programming,
+        &session.timeouts,
+        |pos, segment| {
+            for range in checker.push(pos, &segment) {
+                print_range(&range);
+                count += 1;
+            }
+            Ok(())
+        },
+    )?;
+    if let Some(range) = checker.finish() {
+        print_range(&range);
+        count += 1;
+    }
+    reboot_into_app(transport, &session.programming, &session.timeouts)?;
+    Ok((count, address..address + length))
+}
+
+fn print_range(range: &NonBlankRange) {
+    println!(
+        "Not blank 0x{:08X}..0x{:08X} (0x{:X} bytes), first byte 0x{:02X}",
+        range.start,
+        range.end,
+        range.end - range.start,
+        range.first_byte
+    );
+}
@@ -0,0 +1,100 @@
+use crate::beslink::{load_capture, CaptureEvent, DecodedItem, Direction, FrameDecoder};
+use std::fs;
+use std::path::PathBuf;
+use tracing::error;
+
+// Captures made with --record are text and carry the direction of every byte,
+// anything else is treated as a raw dump of the uart (optionally with the device side in a second file)
+pub fn cmd_decode(capture_path: &PathBuf, rx_capture_path: &Option<PathBuf>) {
+    let capture = match fs::read(capture_path) {
+        Ok(capture) => capture,
+        Err(e) => {
+            error!("Could not read {}: {e}", capture_path.display());
+            std::process::exit(1);
+        }
+    };
+    let mut decoder = FrameDecoder::new();
+    if capture.starts_with(b"# bestool capture") {
+        let events = match load_capture(capture_path) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Could not parse {}: {e}", capture_path.display());
+                std::process::exit(1);
+            }
+        };
+        for (_, event) in events {
+            let items = match event {
+                CaptureEvent::Tx(data) => decoder.push(Direction::Tx, &data),
+                CaptureEvent::Rx(data) => decoder.push(Direction::Rx, &data),
+                CaptureEvent::ReadError(_) => continue,
+                CaptureEvent::Baud(baud) => {
+                    println!("-- baud rate changed to {baud}");
+                    continue;
+                }
+            };
+            print_items(&items);
+        }
+    } else {
+        match rx_capture_path {
+            Some(rx_capture_path) => {
+                let rx_capture = match fs::read(rx_capture_path) {
+                    Ok(rx_capture) => rx_capture,
+                    Err(e) => {
+                        error!("Could not read {}: {e}", rx_capture_path.display());
+                        std::process::exit(1);
+                    }
+                };
+                print_items(&decoder.push(Direction::Tx, &capture));
+                print_items(&decoder.push(Direction::Rx, &rx_capture));
+            }
+            None => print_items(&decoder.push(Direction::Unknown, &capture)),
+        }
+    }
+    print_items(&decoder.finish());
+}
+
+fn print_items(items: &[DecodedItem]) {
+    for item in items {
+        match item {
+            DecodedItem::Frame {
+                direction,
+                offset,
+                frame,
+                checksum_ok,
+                description,
+            } => {
+                let checksum = if *checksum_ok { "" } else { " BAD CHECKSUM" };
+                println!(
+                    "{} 0x{offset:08X} {description}{checksum} {frame:02X?}",
+                    direction_label(*direction)
+                );
+            }
+            DecodedItem::RawData {
+                direction,
+                offset,
+                length,
+                what,
+            } => println!(
+                "{} 0x{offset:08X} <{length} bytes of {what}>",
+                direction_label(*direction)
+            ),
+            DecodedItem::Garbage {
+                direction,
+                offset,
+                data,
+            } => println!(
+                "{} 0x{offset:08X} <{} bytes outside any frame> {data:02X?}",
+                direction_label(*direction),
+                data.len()
+            ),
+        }
+    }
+}
+
+fn direction_label(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "TX",
+        Direction::Rx => "RX",
+        Direction::Unknown => "--",
+    }
+}
@@ -0,0 +1,94 @@
+use super::Session;
+use crate::beslink::{
+    check_flash_range, detected_flash_size, erase_flash, flash_sector_size,
+    helper_sync_and_load_programmer, reboot_into_app, BESLinkError, BesTransport,
+};
+use crate::serial_port_opener::open_transport_with_wait;
+use std::io::BufRead;
+use std::time::Duration;
+use tracing::error;
+use tracing::info;
+
+pub enum EraseRange {
+    Range { offset: usize, length: usize },
+    All { confirmed: bool },
+}
+
+pub fn cmd_erase(port_name: &str, range: &EraseRange, wait_for_port: bool, session: &Session) {
+    // Asked before touching the device, so saying no leaves it as it was
+    if let EraseRange::All { confirmed: false } = range
+        && !confirm_whole_chip_erase()
+    {
+        println!("Not erasing");
+        return;
+    }
+    let baud_rate = session.programming.programming_baud_rate();
+    println!("Erasing flash on {port_name} @ {baud_rate}");
+    let mut port =
+        open_transport_with_wait(port_name, baud_rate, wait_for_port, &session.transport);
+    port.set_timeout(Duration::from_millis(5000))
+        .expect("Cant set port timeout");
+
+    let _ = port.clear_buffers();
+    match do_erase(&mut port, range, session) {
+        Ok(_) => {
+            info!("Done...");
+        }
+        Err(e) => {
+            error!("Failed {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn do_erase(
+    transport: &mut dyn BesTransport,
+    range: &EraseRange,
+    session: &Session,
+) -> Result<(), BESLinkError> {
+    info!("Starting loader and checking communications");
+    let device_info =
+        helper_sync_and_load_programmer(transport, &session.programming, &session.timeouts)?;
+    let flash_size = detected_flash_size(&device_info.flash_id);
+    let (offset, length) = match range {
+        EraseRange::Range { offset, length } => (*offset, *length),
+        EraseRange::All { .. } => {
+            let flash_id = device_info.flash_id;
+            let Some(size) = flash_size else {
+                error!(
+                    "Unable to work out the flash size from ID {:02X}{:02X}{:02X}",
+                    flash_id.manufacturer, flash_id.memory_type, flash_id.capacity
+                );
+                return Err(BESLinkError::InvalidArgs);
+            };
+            (0, size)
+        }
+    };
+    let flash_base = device_info.chip.flash_base;
+    check_flash_range(flash_base + offset, length, flash_base, flash_size)?;
+    info!(
+        "Erasing 0x{:X} bytes from 0x{:08X}",
+        length,
+        flash_base + offset
+    );
+    erase_flash(
+        transport,
+        flash_base + offset,
+        length,
+        flash_base,
+        flash_size,
+        flash_sector_size(&device_info.flash_id),
+        &session.timeouts,
+    )?;
+    reboot_into_app(transport, &session.programming, &session.timeouts)?;
+    Ok(())
+}
+
+fn confirm_whole_chip_erase() -> bool {
+    println!("This will erase the whole flash, including the bootloader. Type 'yes' to continue:");
+    let mut answer = String::new();
+    match std::io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => answer.trim() == "yes",
+        Err(_) => false,
+    }
+}
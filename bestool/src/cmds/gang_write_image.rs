@@ -0,0 +1,240 @@
+use super::write_image::{do_burn_image_to_flash, WriteOptions};
+use super::Session;
+use crate::beslink::{helper_sync_and_load_programmer, BESLinkError, FlashUniqueId, Stage};
+use crate::serial_port_opener::{open_transport, TransportOptions};
+use std::any::Any;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{error, info, info_span};
+
+struct PortResult {
+    port_name: String,
+    unique_id: Option<FlashUniqueId>,
+    outcome: Result<(), String>,
+}
+
+// Runs the whole write on every port at once; log lines carry the port they came from.
+// Exits non-zero unless every device passed
+pub fn cmd_gang_write_image(
+    input_file: &PathBuf,
+    port_names: &[String],
+    options: &WriteOptions,
+    wait_for_port: bool,
+    session: &Session,
+) {
+    if port_names.is_empty() {
+        error!("No ports to write to");
+        std::process::exit(1);
+    }
+    println!(
+        "Writing binary data to {} ports @ {}",
+        port_names.len(),
+        session.programming.programming_baud_rate()
+    );
+    // Each port gets its own capture file
+    let session = Session {
+        transport: TransportOptions {
+            record_per_port: true,
+            ..session.transport.clone()
+        },
+        ..session.clone()
+    };
+    let results = write_to_ports(input_file, port_names, options, wait_for_port, &session);
+    print_results(&results);
+    if results.iter().any(|result| result.outcome.is_err()) {
+        std::process::exit(1);
+    }
+}
+
+fn write_to_ports(
+    input_file: &PathBuf,
+    port_names: &[String],
+    options: &WriteOptions,
+    wait_for_port: bool,
+    session: &Session,
+) -> Vec<PortResult> {
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = port_names
+            .iter()
+            .map(|port_name| {
+                scope.spawn(move || {
+                    let _span = info_span!("port", name = %port_name).entered();
+                    write_to_port(input_file, port_name, options, wait_for_port, session)
+                })
+            })
+            .collect();
+        port_names
+            .iter()
+            .zip(workers)
+            .map(|(port_name, worker)| {
+                // A panic only takes down that port's thread, it is reported like any other failure
+                worker.join().unwrap_or_else(|panic| PortResult {
+                    port_name: port_name.clone(),
+                    unique_id: None,
+                    outcome: Err(panic_message(panic)),
+                })
+            })
+            .collect()
+    })
+}
+
+fn write_to_port(
+    input_file: &PathBuf,
+    port_name: &str,
+    options: &WriteOptions,
+    wait_for_port: bool,
+    session: &Session,
+) -> PortResult {
+    let mut result = PortResult {
+        port_name: port_name.to_owned(),
+        unique_id: None,
+        outcome: Ok(()),
+    };
+    // A port that never shows up fails on its own instead of holding up the summary
+    let deadline = session.timeouts.deadline(Stage::Open);
+    let mut port = match open_transport(
+        port_name,
+        session.programming.programming_baud_rate(),
+        wait_for_port,
+        Some(&deadline),
+        &session.transport,
+    ) {
+        Ok(port) => port,
+        Err(e) => {
+            error!("Failed {}", e);
+            result.outcome = Err(e.to_string());
+            return result;
+        }
+    };
+    port.set_timeout(Duration::from_millis(5000))
+        .expect("Cant set port timeout");
+
+    let _ = port.clear_buffers();
+    info!("Starting loader and checking communications");
+    let outcome =
+        helper_sync_and_load_programmer(&mut port, &session.programming, &session.timeouts)
+            .and_then(|device_info| {
+                result.unique_id = Some(device_info.unique_id.clone());
+                info!("Now doing firmware load");
+                do_burn_image_to_flash(input_file, &mut port, options, &device_info, session)
+            });
+    match &outcome {
+        Ok(_) => info!("Done..."),
+        Err(e) => error!("Failed {}", e),
+    }
+    result.outcome = outcome.map_err(|e: BESLinkError| e.to_string());
+    result
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "Panicked".to_owned(),
+        },
+    }
+}
+
+fn print_results(results: &[PortResult]) {
+    let port_width = results
+        .iter()
+        .map(|result| result.port_name.len())
+        .chain(std::iter::once("Port".len()))
+        .max()
+        .unwrap_or_default();
+    let id_width = results
+        .iter()
+        .filter_map(|result| result.unique_id.as_ref())
+        .map(|unique_id| unique_id.id.len() * 2)
+        .chain(std::iter::once("Unique ID".len()))
+        .max()
+        .unwrap_or_default();
+    println!();
+    println!("{:port_width$}  Result  {:id_width$}", "Port", "Unique ID");
+    for result in results {
+        let unique_id = match &result.unique_id {
+            Some(unique_id) => unique_id.id.iter().map(|b| format!("{b:02X}")).collect(),
+            None => "-".to_owned(),
+        };
+        match &result.outcome {
+            Ok(_) => println!("{:port_width$}  PASS    {unique_id}", result.port_name),
+            Err(e) => println!(
+                "{:port_width$}  FAIL    {unique_id:id_width$}  {e}",
+                result.port_name
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::simulator::DeviceSimulator;
+    use crate::beslink::{
+        helper_sync_and_load_programmer, BesTransport, ProgrammingOptions, RecordingTransport,
+        Timeouts,
+    };
+    use crate::cmds::gang_write_image::write_to_ports;
+    use crate::cmds::write_image::{do_burn_image_to_flash, WriteOptions};
+    use crate::cmds::Session;
+    use crate::serial_port_opener::TransportOptions;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_missing_port_fails_without_holding_up_the_others() {
+        let dir = std::env::temp_dir().join(format!("bestool-gang-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("app.bin");
+        std::fs::write(
+            &image_path,
+            (0..0x1000).map(|i| i as u8).collect::<Vec<u8>>(),
+        )
+        .unwrap();
+        let options = WriteOptions {
+            address: None,
+            verify: true,
+            incremental: false,
+        };
+        let session = Session {
+            programming: ProgrammingOptions::default(),
+            timeouts: Timeouts {
+                open: Duration::from_millis(500),
+                ..Timeouts::default()
+            },
+            transport: TransportOptions::default(),
+        };
+
+        // The passing port plays back the simulator writing the same image
+        let capture_path = dir.join("capture.txt");
+        {
+            let sim: Box<dyn BesTransport> = Box::new(DeviceSimulator::new());
+            let mut recorder = RecordingTransport::new(sim, &capture_path).unwrap();
+            let device_info = helper_sync_and_load_programmer(
+                &mut recorder,
+                &session.programming,
+                &session.timeouts,
+            )
+            .unwrap();
+            do_burn_image_to_flash(&image_path, &mut recorder, &options, &device_info, &session)
+                .unwrap();
+        }
+        // Nothing listens on the failing port once the listener is gone
+        let missing_port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("tcp://{}", listener.local_addr().unwrap())
+        };
+
+        let port_names = vec![format!("replay:{}", capture_path.display()), missing_port];
+        let results = write_to_ports(&image_path, &port_names, &options, true, &session);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].outcome, Ok(()));
+        assert!(results[0].unique_id.is_some());
+        assert_eq!(
+            results[1].outcome,
+            Err("Communications timed out during port open".to_owned())
+        );
+        assert!(results[1].unique_id.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use super::Session;
+use crate::beslink::{
+    detected_flash_size, flash_sector_size, helper_sync_and_load_programmer, lookup_flash_part,
+    manufacturer_name, reboot_into_app, DeviceInfo, FlashId,
+};
+use crate::serial_port_opener::open_transport_with_wait;
+use std::time::Duration;
+use tracing::error;
+
+pub fn cmd_info(port_name: &str, json: bool, wait_for_port: bool, session: &Session) {
+    let baud_rate = session.programming.programming_baud_rate();
+    if !json {
+        println!("Querying {port_name} @ {baud_rate}");
+    }
+    let mut port =
+        open_transport_with_wait(port_name, baud_rate, wait_for_port, &session.transport);
+    port.set_timeout(Duration::from_millis(5000))
+        .expect("Cant set port timeout");
+
+    let _ = port.clear_buffers();
+    let device_info =
+        match helper_sync_and_load_programmer(&mut port, &session.programming, &session.timeouts) {
+            Ok(device_info) => device_info,
+            Err(e) => {
+                error!("Failed {}", e);
+                std::process::exit(1);
+            }
+        };
+    if json {
+        println!("{}", json_report(&device_info));
+    } else {
+        print_report(&device_info);
+    }
+    // Leave the device running its firmware again
+    if let Err(e) = reboot_into_app(&mut port, &session.programming, &session.timeouts) {
+        error!("Failed to reboot the device {}", e);
+    }
+}
+
+fn print_report(device_info: &DeviceInfo) {
+    let flash_id = &device_info.flash_id;
+    println!("Chip:                {}", device_info.chip.name);
+    println!("ROM sync version:    0x{:04X}", device_info.rom_version);
+    println!(
+        "Programmer version:  0x{:04X}",
+        device_info.programmer.version
+    );
+    println!(
+        "Programmer sectors:  0x{:X}",
+        device_info.programmer.sector_size
+    );
+    println!(
+        "Flash manufacturer:  {} (0x{:02X})",
+        vendor(flash_id).unwrap_or("Unknown"),
+        flash_id.manufacturer
+    );
+    println!(
+        "Flash device:        0x{:02X}{:02X}",
+        flash_id.memory_type, flash_id.capacity
+    );
+    println!(
+        "Flash part:          {}",
+        lookup_flash_part(flash_id).map_or("Unknown", |part| part.name)
+    );
+    match detected_flash_size(flash_id) {
+        Some(size) => println!(
+            "Flash capacity:      {} (0x{:02X})",
+            format_size(size),
+            flash_id.capacity
+        ),
+        None => println!("Flash capacity:      Unknown (0x{:02X})", flash_id.capacity),
+    }
+    match lookup_flash_part(flash_id) {
+        Some(part) => println!("Flash sector size:   {}", format_size(part.sector_size)),
+        None => println!(
+            "Flash sector size:   Unknown, writes are kept to {} boundaries",
+            format_size(flash_sector_size(flash_id))
+        ),
+    }
+    println!("Flash unique ID:     {}", hex(&device_info.unique_id.id));
+}
+
+// Written by hand to avoid pulling in serde for one small object; every string is hex or from our own tables
+fn json_report(device_info: &DeviceInfo) -> String {
+    let flash_id = &device_info.flash_id;
+    let json_string = |value: Option<&str>| match value {
+        Some(value) => format!("\"{value}\""),
+        None => "null".to_owned(),
+    };
+    format!(
+        concat!(
+            "{{\"chip\":\"{}\",\"rom_version\":\"0x{:04X}\",\"programmer_version\":\"0x{:04X}\",\"sector_size\":{},",
+            "\"flash\":{{\"manufacturer_id\":\"0x{:02X}\",\"manufacturer\":{},\"device_id\":\"0x{:02X}{:02X}\",",
+            "\"capacity_id\":\"0x{:02X}\",\"part\":{},\"size\":{},\"sector_size\":{}}},\"unique_id\":\"{}\"}}"
+        ),
+        device_info.chip.name,
+        device_info.rom_version,
+        device_info.programmer.version,
+        device_info.programmer.sector_size,
+        flash_id.manufacturer,
+        json_string(vendor(flash_id)),
+        flash_id.memory_type,
+        flash_id.capacity,
+        flash_id.capacity,
+        json_string(lookup_flash_part(flash_id).map(|part| part.name)),
+        detected_flash_size(flash_id).map_or("null".to_owned(), |size| size.to_string()),
+        lookup_flash_part(flash_id).map_or("null".to_owned(), |part| part.sector_size.to_string()),
+        hex(&device_info.unique_id.id),
+    )
+}
+
+// The parts table names the vendor more precisely where JEP106 codes are shared
+fn vendor(flash_id: &FlashId) -> Option<&'static str> {
+    lookup_flash_part(flash_id)
+        .map(|part| part.vendor)
+        .or(manufacturer_name(flash_id.manufacturer))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn format_size(size: usize) -> String {
+    if size >= 1024 * 1024 && size.is_multiple_of(1024 * 1024) {
+        format!("{}MiB", size / (1024 * 1024))
+    } else {
+        format!("{}KiB", size / 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::{find_chip, DeviceInfo, FlashId, FlashUniqueId, ProgrammerInfo};
+    use crate::cmds::info::json_report;
+
+    #[test]
+    fn test_json_report() {
+        let device_info = DeviceInfo {
+            chip: find_chip("BES2300").unwrap(),
+            rom_version: 0x0100,
+            programmer: ProgrammerInfo {
+                version: 0x0103,
+                sector_size: 0x9000,
+            },
+            flash_id: FlashId {
+                manufacturer: 0xC8,
+                memory_type: 0x60,
+                capacity: 0x16,
+            },
+            unique_id: FlashUniqueId {
+                id: vec![0x30, 0x31, 0xFF],
+            },
+        };
+        assert_eq!(
+            json_report(&device_info),
+            concat!(
+                r#"{"chip":"BES2300","rom_version":"0x0100","programmer_version":"0x0103","sector_size":36864,"#,
+                r#""flash":{"manufacturer_id":"0xC8","manufacturer":"GigaDevice","device_id":"0x6016","#,
+                r#""capacity_id":"0x16","part":"GD25LQ32","size":4194304,"sector_size":4096},"unique_id":"3031FF"}"#
+            )
+        );
+    }
+}
@@ -1,11 +1,25 @@
+mod blank_check;
+mod decode;
+mod erase;
+mod gang_write_image;
+mod info;
 mod list_ports;
 mod read_image;
 mod serial_monitor;
+mod session;
 mod write_image;
 mod write_image_then_monitor;
 
+pub use self::blank_check::cmd_blank_check;
+pub use self::decode::cmd_decode;
+pub use self::erase::cmd_erase;
+pub use self::erase::EraseRange;
+pub use self::gang_write_image::cmd_gang_write_image;
+pub use self::info::cmd_info;
 pub use self::list_ports::cmd_list_serial_ports;
 pub use self::read_image::cmd_read_image;
 pub use self::serial_monitor::cmd_serial_port_monitor;
+pub use self::session::Session;
 pub use self::write_image::cmd_write_image;
+pub use self::write_image::WriteOptions;
 pub use self::write_image_then_monitor::cmd_write_image_then_monitor;
@@ -1,97 +1,237 @@
+use super::Session;
 use crate::beslink::{
-    helper_sync_and_load_programmer, read_flash_data, send_device_reboot, BESLinkError,
-    BES_PROGRAMMING_BAUDRATE,
+    check_flash_range, detected_flash_size, helper_sync_and_load_programmer,
+    read_flash_in_segments, reboot_into_app, BESLinkError, BesTransport, DeviceInfo,
 };
-use crate::serial_port_opener::open_serial_port_with_wait;
-use serialport::SerialPort;
-use std::fs::File;
+use crate::serial_port_opener::open_transport_with_wait;
+use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 pub fn cmd_read_image(
     input_file: &PathBuf,
     port_name: &str,
     start: usize,
-    length: usize,
+    length: Option<usize>,
+    resume: bool,
     wait_for_port: bool,
+    session: &Session,
 ) {
     //First gain sync to the device
-    println!("Reading binary data from {port_name} @ {BES_PROGRAMMING_BAUDRATE}");
-    let mut port = open_serial_port_with_wait(port_name, BES_PROGRAMMING_BAUDRATE, wait_for_port);
+    let baud_rate = session.programming.programming_baud_rate();
+    println!("Reading binary data from {port_name} @ {baud_rate}");
+    let mut port =
+        open_transport_with_wait(port_name, baud_rate, wait_for_port, &session.transport);
     port.set_timeout(Duration::from_millis(5000))
         .expect("Cant set port timeout");
 
-    match do_read_flash_data(input_file, &mut port, start, length) {
+    match do_read_flash_data(input_file, &mut port, start, length, resume, session) {
         Ok(_) => {
             info!("Done...");
         }
         Err(e) => {
             error!("Failed {}", e);
+            std::process::exit(1);
         }
     }
 }
 fn do_read_flash_data(
     output_file_path: &PathBuf,
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
     start: usize,
-    length: usize,
+    length: Option<usize>,
+    resume: bool,
+    session: &Session,
 ) -> Result<(), BESLinkError> {
-    let mut flash_content: Vec<u8> = vec![];
-    const MAX_READ_BEFORE_RESET: usize = 1024 * 1024; //1MiB chunks
-    while flash_content.len() < length {
-        let chunk_length = {
-            if (length - flash_content.len()) < MAX_READ_BEFORE_RESET {
-                length - flash_content.len()
-            } else {
-                MAX_READ_BEFORE_RESET
-            }
-        };
-        let pos = 0x3C00_0000 + start + flash_content.len();
-        info!(
-            "===== Preparing to read flash from 0x{:X} ({}%) to 0x{:X} ({}%) =====",
-            pos,
-            flash_content.len() * 100 / length,
-            pos + chunk_length,
-            (flash_content.len() + chunk_length) * 100 / length,
-        );
-        let chunk = do_reset_sync_read(serial_port, pos, chunk_length)?;
-        flash_content.extend(chunk);
+    info!("Starting loader and checking communications");
+    let device_info =
+        helper_sync_and_load_programmer(transport, &session.programming, &session.timeouts)?;
+    let length = flash_region_length(&device_info, start, length)?;
+    let address = device_info.chip.flash_base + start;
+    let checkpoint_path = checkpoint_path(output_file_path);
+    let done = if resume {
+        resume_point(output_file_path, &checkpoint_path, address, length)?
+    } else {
+        0
+    };
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(done == 0)
+        .open(output_file_path)?;
+    // Anything after the last checkpoint may be from a segment that never finished
+    file.set_len(done as u64)?;
+    file.seek(SeekFrom::End(0))?;
+    let mut checkpoint = ReadCheckpoint {
+        address,
+        length,
+        done,
+    };
+    checkpoint.save(&checkpoint_path)?;
+    if done > 0 {
+        info!("Resuming read at 0x{:08X}", address + done);
     }
-
-    let mut file = File::create(output_file_path)?;
-    // Write a slice of bytes to the file
-    file.write_all(flash_content.as_slice())?;
+    info!("Now doing flash read");
+    // Each segment is on disk before the checkpoint says so, so a crash at any point can be resumed
+    read_flash_in_segments(
+        transport,
+        address + done,
+        length - done,
+        &session.programming,
+        &session.timeouts,
+        |_, segment| {
+            file.write_all(&segment)?;
+            file.sync_data()?;
+            checkpoint.done += segment.len();
+            checkpoint.save(&checkpoint_path)
+        },
+    )?;
+    //Send reset
+    reboot_into_app(transport, &session.programming, &session.timeouts)?;
+    fs::remove_file(&checkpoint_path)?;
 
     Ok(())
 }
 
-//The main bootloader wasn't super designed to allow reading the flash;
-// but they shipped a debugging memory read that will try and dump memory content out to the uart basically.
-// But as this is a "debug" message; it doesnt seem to reset the watchdog
-// This means that reads larger than about 2MB will fail randomly when the watchdog trips
-// To work around this, we read 1MB chunks with a device reset between the reads
+// Where a read of the same region into this file got to; anything that does not add up starts from the beginning
+fn resume_point(
+    output_file_path: &Path,
+    checkpoint_path: &Path,
+    address: usize,
+    length: usize,
+) -> Result<usize, BESLinkError> {
+    let Some(checkpoint) = ReadCheckpoint::load(checkpoint_path)? else {
+        warn!(
+            "No checkpoint at {}, reading from the start",
+            checkpoint_path.display()
+        );
+        return Ok(0);
+    };
+    if checkpoint.address != address || checkpoint.length != length {
+        warn!(
+            "Checkpoint is for 0x{:X} bytes at 0x{:08X}, not 0x{:X} bytes at 0x{:08X}; reading from the start",
+            checkpoint.length, checkpoint.address, length, address
+        );
+        return Ok(0);
+    }
+    let on_disk = fs::metadata(output_file_path).map_or(0, |metadata| metadata.len() as usize);
+    if on_disk < checkpoint.done {
+        warn!("Output file is shorter than the checkpoint says, reading from the start");
+        return Ok(0);
+    }
+    Ok(checkpoint.done)
+}
+
+fn checkpoint_path(output_file_path: &Path) -> PathBuf {
+    let mut path = output_file_path.as_os_str().to_owned();
+    path.push(".checkpoint");
+    PathBuf::from(path)
+}
 
-fn do_reset_sync_read(
-    serial_port: &mut Box<dyn SerialPort>,
-    start: usize,
+// Sidecar kept next to the dump while it is being read, e.g.
+//   address 0x3C000000
+//   length 0x400000
+//   done 0x200000
+#[derive(Debug, PartialEq)]
+struct ReadCheckpoint {
+    address: usize,
     length: usize,
-) -> Result<Vec<u8>, BESLinkError> {
-    info!("Starting loader and checking communications");
-    match helper_sync_and_load_programmer(serial_port) {
-        Ok(_) => {
-            info!("Done...Bootloader start");
-        }
-        Err(e) => {
-            error!("Failed {}", e);
+    done: usize,
+}
+
+impl ReadCheckpoint {
+    fn load(path: &Path) -> Result<Option<Self>, BESLinkError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let field = |name: &str| {
+            text.lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|(key, _)| *key == name)
+                .and_then(|(_, value)| value.strip_prefix("0x"))
+                .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+        };
+        match (field("address"), field("length"), field("done")) {
+            (Some(address), Some(length), Some(done)) if done <= length => {
+                Ok(Some(ReadCheckpoint {
+                    address,
+                    length,
+                    done,
+                }))
+            }
+            _ => {
+                warn!("Ignoring unreadable checkpoint {}", path.display());
+                Ok(None)
+            }
         }
     }
-    info!("Now doing flash read");
-    //Dump device flash from 0x3C000000 to local file
-    let flash_content = read_flash_data(serial_port, start, length)?;
-    //Send reset
-    send_device_reboot(serial_port)?;
-    Ok(flash_content)
+
+    // Written aside and renamed into place, so the checkpoint is never half written
+    fn save(&self, path: &Path) -> Result<(), BESLinkError> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(
+            &temp_path,
+            format!(
+                "address 0x{:X}\nlength 0x{:X}\ndone 0x{:X}\n",
+                self.address, self.length, self.done
+            ),
+        )?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+// Without a length the region runs to the end of the detected flash
+pub fn flash_region_length(
+    device_info: &DeviceInfo,
+    start: usize,
+    length: Option<usize>,
+) -> Result<usize, BESLinkError> {
+    let flash_size = detected_flash_size(&device_info.flash_id);
+    let Some(length) = length.or(flash_size.map(|size| size.saturating_sub(start))) else {
+        error!(
+            "Unable to work out the flash size from ID {:02X}{:02X}{:02X}, give a --length",
+            device_info.flash_id.manufacturer,
+            device_info.flash_id.memory_type,
+            device_info.flash_id.capacity
+        );
+        return Err(BESLinkError::InvalidArgs);
+    };
+    let flash_base = device_info.chip.flash_base;
+    check_flash_range(flash_base + start, length, flash_base, flash_size)?;
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmds::read_image::{checkpoint_path, ReadCheckpoint};
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let dir = std::env::temp_dir().join(format!("bestool-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = checkpoint_path(&dir.join("dump.bin"));
+        assert_eq!(path, dir.join("dump.bin.checkpoint"));
+        assert_eq!(ReadCheckpoint::load(&path).unwrap(), None);
+
+        let checkpoint = ReadCheckpoint {
+            address: 0x3C00_0000,
+            length: 0x40_0000,
+            done: 0x20_0000,
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(ReadCheckpoint::load(&path).unwrap(), Some(checkpoint));
+
+        std::fs::write(&path, "done 0x10\n").unwrap();
+        assert_eq!(ReadCheckpoint::load(&path).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
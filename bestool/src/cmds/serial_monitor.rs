@@ -1,9 +1,15 @@
-use crate::{serial_monitor::run_serial_monitor, serial_port_opener::open_serial_port_with_wait};
+use crate::serial_monitor::run_serial_monitor;
+use crate::serial_port_opener::{open_transport_with_wait, TransportOptions};
 
-pub fn cmd_serial_port_monitor(port_name: &str, baud_rate: u32, wait_for_port: bool) {
+pub fn cmd_serial_port_monitor(
+    port_name: &str,
+    baud_rate: u32,
+    wait_for_port: bool,
+    transport: &TransportOptions,
+) {
     // Span a basic serial port monitor attached to the serial port
     // Eventually we will hook in extra utility commands
-    let port = open_serial_port_with_wait(port_name, baud_rate, wait_for_port);
+    let port = open_transport_with_wait(port_name, baud_rate, wait_for_port, transport);
 
     run_serial_monitor(port).unwrap();
 }
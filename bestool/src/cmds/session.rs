@@ -0,0 +1,10 @@
+use crate::beslink::{ProgrammingOptions, Timeouts};
+use crate::serial_port_opener::TransportOptions;
+
+// Everything a command needs to know about the device and the link to it, worked out once from the command line
+#[derive(Clone)]
+pub struct Session {
+    pub programming: ProgrammingOptions,
+    pub timeouts: Timeouts,
+    pub transport: TransportOptions,
+}
@@ -1,51 +1,142 @@
+use super::Session;
 use crate::beslink::{
-    burn_image_to_flash, helper_sync_and_load_programmer, send_device_reboot, BESLinkError,
-    BES_PROGRAMMING_BAUDRATE,
+    burn_image_to_flash, burn_image_to_flash_incremental, check_burn_address, check_flash_range,
+    detected_flash_size, flash_regions, flash_sector_size, helper_sync_and_load_programmer,
+    load_image_segments, reboot_into_app, verify_flash, BESLinkError, BesTransport, DeviceInfo,
+    ImageSegment, FLASH_BUFFER_SIZE,
 };
-use crate::serial_port_opener::open_serial_port_with_wait;
-use serialport::{ClearBuffer, SerialPort};
+use crate::serial_port_opener::open_transport_with_wait;
 use std::fs;
 use std::path::PathBuf;
 use std::time::Duration;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
-pub fn cmd_write_image(input_file: &PathBuf, port_name: &str, wait_for_port: bool) {
+// How the image is put into flash; shared with write-image-then-monitor
+pub struct WriteOptions {
+    // Defaults to the start of flash; only raw binaries use it, other formats carry their own addresses
+    pub address: Option<usize>,
+    pub verify: bool,
+    pub incremental: bool,
+}
+
+pub fn cmd_write_image(
+    input_file: &PathBuf,
+    port_name: &str,
+    options: &WriteOptions,
+    wait_for_port: bool,
+    session: &Session,
+) {
     //First gain sync to the device
-    println!("Writing binary data to {port_name} @ {BES_PROGRAMMING_BAUDRATE}");
-    let mut port = open_serial_port_with_wait(port_name, BES_PROGRAMMING_BAUDRATE, wait_for_port);
+    let baud_rate = session.programming.programming_baud_rate();
+    println!("Writing binary data to {port_name} @ {baud_rate}");
+    let mut port =
+        open_transport_with_wait(port_name, baud_rate, wait_for_port, &session.transport);
     port.set_timeout(Duration::from_millis(5000))
         .expect("Cant set port timeout");
 
-    let _ = port.clear(ClearBuffer::All);
+    let _ = port.clear_buffers();
     info!("Starting loader and checking communications");
-    match helper_sync_and_load_programmer(&mut port) {
-        Ok(_) => {
-            info!("Done...");
-        }
-        Err(e) => {
-            error!("Failed {}", e);
-            return;
-        }
-    }
+    let device_info =
+        match helper_sync_and_load_programmer(&mut port, &session.programming, &session.timeouts) {
+            Ok(device_info) => {
+                info!("Done...");
+                device_info
+            }
+            Err(e) => {
+                error!("Failed {}", e);
+                // Scripts and CI go by the exit code, so every failure has to show up in it
+                std::process::exit(1);
+            }
+        };
     info!("Now doing firmware load");
-    match do_burn_image_to_flash(input_file, &mut port) {
+    match do_burn_image_to_flash(input_file, &mut port, options, &device_info, session) {
         Ok(_) => {
             info!("Done...");
         }
         Err(e) => {
             error!("Failed {}", e);
+            std::process::exit(1);
         }
     }
 }
-fn do_burn_image_to_flash(
+pub fn do_burn_image_to_flash(
     input_file: &PathBuf,
-    serial_port: &mut Box<dyn SerialPort>,
+    transport: &mut dyn BesTransport,
+    options: &WriteOptions,
+    device_info: &DeviceInfo,
+    session: &Session,
 ) -> Result<(), BESLinkError> {
-    // Open file, read file, call burn_image_to_flash
-    let file_contents = fs::read(input_file)?;
-    burn_image_to_flash(serial_port, file_contents, 0x3C00_0000)?;
+    let flash_base = device_info.chip.flash_base;
+    let sector_size = flash_sector_size(&device_info.flash_id);
+    let regions = match load_image_segments(input_file)? {
+        Some(segments) => {
+            if options.address.is_some() {
+                warn!("Ignoring --address, the image says where each part of it goes");
+            }
+            flash_regions(
+                segments,
+                flash_base,
+                detected_flash_size(&device_info.flash_id),
+                sector_size,
+            )?
+        }
+        None => vec![ImageSegment {
+            address: options.address.unwrap_or(flash_base),
+            data: fs::read(input_file)?,
+        }],
+    };
+    // Refuse before anything is erased
+    for region in &regions {
+        check_burn_address(region.address, flash_base, sector_size)?;
+        // The image is padded out to whole flash buffers when it is burnt
+        check_flash_range(
+            region.address,
+            region.data.len().next_multiple_of(FLASH_BUFFER_SIZE),
+            flash_base,
+            detected_flash_size(&device_info.flash_id),
+        )?;
+    }
+    for region in &regions {
+        info!(
+            "Writing 0x{:X} bytes at 0x{:08X}",
+            region.data.len(),
+            region.address
+        );
+        if options.incremental {
+            let skipped = burn_image_to_flash_incremental(
+                transport,
+                region.data.clone(),
+                region.address,
+                sector_size,
+                &session.programming,
+                &session.timeouts,
+            )?;
+            println!("Skipped 0x{skipped:X} bytes that were already up to date");
+        } else {
+            burn_image_to_flash(
+                transport,
+                region.data.clone(),
+                region.address,
+                &session.timeouts,
+            )?;
+        }
+    }
+    if options.verify {
+        // A mismatch returns before the reboot, so the device is not left running a corrupt image
+        info!("Reading back to verify");
+        for region in &regions {
+            verify_flash(
+                transport,
+                region.address,
+                &region.data,
+                &session.programming,
+                &session.timeouts,
+            )?;
+        }
+    }
     //Send reset
-    send_device_reboot(serial_port)?;
+    reboot_into_app(transport, &session.programming, &session.timeouts)?;
     Ok(())
 }
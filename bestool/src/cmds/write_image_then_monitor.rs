@@ -1,12 +1,8 @@
-use crate::beslink::{
-    burn_image_to_flash, helper_sync_and_load_programmer, send_device_reboot, BESLinkError,
-    BES_PROGRAMMING_BAUDRATE,
-};
+use super::write_image::{do_burn_image_to_flash, WriteOptions};
+use super::Session;
+use crate::beslink::helper_sync_and_load_programmer;
 use crate::serial_monitor::run_serial_monitor;
-use crate::serial_port_opener::open_serial_port_with_wait;
-use serialport::{ClearBuffer, SerialPort};
-
-use std::fs;
+use crate::serial_port_opener::open_transport_with_wait;
 
 use std::path::PathBuf;
 use std::time::Duration;
@@ -16,36 +12,43 @@ use tracing::info;
 pub fn cmd_write_image_then_monitor(
     input_file_path: &PathBuf,
     serial_port: &str,
+    options: &WriteOptions,
     monitor_baud_rate: u32,
     wait_for_port: bool,
+    session: &Session,
 ) {
     //First gain sync to the device
+    let baud_rate = session.programming.programming_baud_rate();
     println!(
-        "Writing binary data to {serial_port} @ {BES_PROGRAMMING_BAUDRATE}; then monitoring at {monitor_baud_rate}"
+        "Writing binary data to {serial_port} @ {baud_rate}; then monitoring at {monitor_baud_rate}"
     );
-    let mut port = open_serial_port_with_wait(serial_port, BES_PROGRAMMING_BAUDRATE, wait_for_port);
+    let mut port =
+        open_transport_with_wait(serial_port, baud_rate, wait_for_port, &session.transport);
     port.set_timeout(Duration::from_millis(5000))
         .expect("Cant set port timeout");
 
-    let _ = port.clear(ClearBuffer::All);
+    let _ = port.clear_buffers();
     info!("Starting loader and checking communications");
-    match helper_sync_and_load_programmer(&mut port) {
-        Ok(_) => {
-            info!("Done...");
-        }
-        Err(e) => {
-            error!("Failed {}", e);
-            return;
-        }
-    }
+    let device_info =
+        match helper_sync_and_load_programmer(&mut port, &session.programming, &session.timeouts) {
+            Ok(device_info) => {
+                info!("Done...");
+                device_info
+            }
+            Err(e) => {
+                error!("Failed {}", e);
+                // Scripts and CI go by the exit code, so every failure has to show up in it
+                std::process::exit(1);
+            }
+        };
     info!("Now doing firmware load");
-    match do_burn_image_to_flash(input_file_path, &mut port) {
+    match do_burn_image_to_flash(input_file_path, &mut port, options, &device_info, session) {
         Ok(_) => {
             info!("Done...");
         }
         Err(e) => {
             error!("Failed {}", e);
-            return;
+            std::process::exit(1);
         }
     }
     info!("Starting monitoring");
@@ -55,25 +58,14 @@ pub fn cmd_write_image_then_monitor(
         }
         Err(e) => {
             error!("Failed {}", e);
-            return;
+            std::process::exit(1);
         }
     }
     match run_serial_monitor(port) {
         Ok(_) => {}
         Err(e) => {
             error!("Failed monitoring: {}", e);
+            std::process::exit(1);
         }
     }
 }
-fn do_burn_image_to_flash(
-    input_file: &PathBuf,
-    serial_port: &mut Box<dyn SerialPort>,
-) -> Result<(), BESLinkError> {
-    // Open file, read file, call burn_image_to_flash
-    let file_contents = fs::read(input_file)?;
-
-    burn_image_to_flash(serial_port, file_contents, 0x3C00_0000)?;
-    //Send reset
-    send_device_reboot(serial_port)?;
-    Ok(())
-}
@@ -2,12 +2,19 @@ mod beslink;
 mod cmds;
 mod serial_monitor;
 mod serial_port_opener;
+use crate::beslink::{
+    chip_names, find_chip, read_programmer, ChipProfile, ProgrammerImage, ProgrammingOptions,
+    ResetSequences, Timeouts,
+};
 use crate::cmds::{
-    cmd_list_serial_ports, cmd_read_image, cmd_serial_port_monitor, cmd_write_image,
-    cmd_write_image_then_monitor,
+    cmd_blank_check, cmd_decode, cmd_erase, cmd_gang_write_image, cmd_info, cmd_list_serial_ports,
+    cmd_read_image, cmd_serial_port_monitor, cmd_write_image, cmd_write_image_then_monitor,
+    EraseRange, Session, WriteOptions,
 };
+use crate::serial_port_opener::{ports_matching, TransportOptions};
 use clap::Parser;
-use tracing::Level;
+use std::time::Duration;
+use tracing::{error, Level};
 
 // BES2300 programming utility for better cross platform support
 // This is completely reverse engineered at this point; there ~may~ will be bugs
@@ -21,18 +28,95 @@ use tracing::Level;
 #[derive(Parser, Debug)] // requires `derive` feature
 #[command(name = "bestool")]
 #[command(bin_name = "bestool")]
+struct Cli {
+    #[command(subcommand)]
+    command: BesTool,
+    #[command(flatten)]
+    global: GlobalArgs,
+}
+
+// Options that apply to every command, and to the command a replay runs
+#[derive(clap::Args, Debug)]
+struct GlobalArgs {
+    /// Record every byte sent to and read from the device, with timestamps, into this capture file
+    #[arg(long, global = true)]
+    record: Option<std::path::PathBuf>,
+    /// Programmer blob to load into the device instead of the built-in one, e.g. a build for another flash part
+    #[arg(long, global = true)]
+    programmer: Option<std::path::PathBuf>,
+    /// BES part on the other end; recognised from its ROM when not given. Only BES2300 is supported so far
+    #[arg(long, global = true, value_parser = parse_chip)]
+    chip: Option<&'static ChipProfile>,
+    /// Experimental: switch the programmer to this baud rate (e.g. 3000000) once it is running, using a command not yet
+    /// seen in a capture; falls back to the chip's default if that fails. Ignored over tcp://
+    #[arg(long, global = true)]
+    baud: Option<u32>,
+    /// Reset the board into its ROM with the DTR/RTS lines: dtr-pulse, rts-pulse, rts-dtr-classic,
+    /// or steps like D1,W100,D0 (then ;<steps> for the reset into the app after programming)
+    #[arg(long, global = true, value_parser = ResetSequences::parse)]
+    reset: Option<ResetSequences>,
+}
+
+impl GlobalArgs {
+    fn session(&self, timeouts: &TimeoutArgs, transport: &TransportOptions) -> Session {
+        Session {
+            programming: ProgrammingOptions {
+                chip: self.chip,
+                reset: self.reset.clone(),
+                baud_rate: self.baud,
+                programmer: self.programmer(),
+            },
+            timeouts: timeouts.timeouts(),
+            transport: transport.clone(),
+        }
+    }
+
+    // Read before the device is touched, so a bad file is reported straight away
+    fn programmer(&self) -> Option<ProgrammerImage> {
+        let path = self.programmer.as_ref()?;
+        match read_programmer(path) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                error!("Failed to load {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
 enum BesTool {
     ListSerialPorts(ListSerialPorts),
+    Info(Info),
     SerialMonitor(SerialMonitor),
     WriteImage(WriteImage),
     WriteImageThenMonitor(WriteImageThenMonitor),
     ReadImage(ReadImage),
+    Erase(Erase),
+    BlankCheck(BlankCheck),
+    Replay(Replay),
+    Decode(Decode),
 }
 
 #[derive(clap::Args, Debug)]
 #[command(author, version, about, long_about = None)]
 struct ListSerialPorts {}
 
+// Loads the programmer and reports what the device and its flash are
+#[derive(clap::Args, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Info {
+    #[arg(short, long)]
+    port: String,
+    /// Print the report as a single JSON object
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    #[arg(short, long, default_value_t = false)]
+    wait: bool,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
+}
+
 #[derive(clap::Args, Debug)]
 #[command(author, version, about, long_about = None)]
 struct SerialMonitor {
@@ -45,22 +129,36 @@ struct SerialMonitor {
 #[derive(clap::Args, Debug)]
 #[command(author, version, about, long_about = None)]
 struct WriteImage {
+    /// Raw binary, or an .elf, .hex or .srec image which says where each part of it goes
     firmware_path: std::path::PathBuf,
-    #[arg(short, long)]
-    port: String,
+    /// Give more than once to write every device at the same time
+    #[arg(short, long, required_unless_present = "port_glob")]
+    port: Vec<String>,
+    /// Write every serial port whose name matches, e.g. '/dev/ttyUSB*'
+    #[arg(long)]
+    port_glob: Option<String>,
+    #[command(flatten)]
+    write: WriteArgs,
     #[arg(short, long, default_value_t = false)]
     wait: bool,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
 }
 #[derive(clap::Args, Debug)]
 #[command(author, version, about, long_about = None)]
 struct WriteImageThenMonitor {
+    /// Raw binary, or an .elf, .hex or .srec image which says where each part of it goes
     firmware_path: std::path::PathBuf,
     #[arg(short, long)]
     port: String,
+    #[command(flatten)]
+    write: WriteArgs,
     #[arg(short, long, default_value_t = 2000000)]
     monitor_baud_rate: u32,
     #[arg(short, long, default_value_t = false)]
     wait: bool,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
 }
 #[derive(clap::Args, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -68,12 +166,155 @@ struct ReadImage {
     firmware_path: std::path::PathBuf,
     #[arg(short, long)]
     port: String,
-    #[arg(short, long, default_value_t = 1024*1024*4)] // default to full flash
-    length: u32,
+    /// Defaults to the rest of the flash, sized from its JEDEC ID
+    #[arg(short, long, value_parser = parse_number)]
+    length: Option<u32>,
     #[arg(short, long, default_value_t = 0)] // default to start of flash
     offset: u32,
+    /// Carry on from the last segment saved by an earlier read into the same file
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+    #[arg(short, long, default_value_t = false)]
+    wait: bool,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
+}
+
+// Erases part of the flash (offsets are from the start of flash), or all of it
+#[derive(clap::Args, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Erase {
+    #[arg(short, long)]
+    port: String,
+    #[arg(short, long, default_value = "0", value_parser = parse_number, conflicts_with = "all")]
+    offset: u32,
+    /// Must be a whole number of blocks that are both flash sectors and 32KiB write buffers: 32KiB on parts with
+    /// the usual 4KiB sectors, 64KiB on parts missing from the flash table. The offset must be on a block too
+    #[arg(short, long, value_parser = parse_number, required_unless_present = "all")]
+    length: Option<u32>,
+    /// Erase the whole chip, bootloader included
+    #[arg(long, default_value_t = false, conflicts_with = "length")]
+    all: bool,
+    /// Do not ask before erasing the whole chip
+    #[arg(long, default_value_t = false)]
+    yes: bool,
     #[arg(short, long, default_value_t = false)]
     wait: bool,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
+}
+
+// Checks that a region of flash (offset from the start of flash) is erased; exits non-zero if not
+#[derive(clap::Args, Debug)]
+#[command(author, version, about, long_about = None)]
+struct BlankCheck {
+    #[arg(short, long)]
+    port: String,
+    #[arg(short, long, default_value = "0", value_parser = parse_number)]
+    offset: u32,
+    /// Defaults to the rest of the flash, sized from its JEDEC ID
+    #[arg(short, long, value_parser = parse_number)]
+    length: Option<u32>,
+    #[arg(short, long, default_value_t = false)]
+    wait: bool,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
+}
+
+// How the image is written, shared by write-image and write-image-then-monitor
+#[derive(clap::Args, Debug)]
+struct WriteArgs {
+    /// Absolute flash address to write a raw binary to, by default the start of flash; must be on a sector boundary of the flash part (usually 4KiB)
+    #[arg(short, long, value_parser = parse_number)]
+    address: Option<u32>,
+    /// Read the written range back and compare it against the image before rebooting
+    #[arg(long, default_value_t = false)]
+    verify: bool,
+    /// Read the flash first and only erase and burn the chunks that differ from the image
+    #[arg(long, default_value_t = false)]
+    incremental: bool,
+}
+
+impl WriteArgs {
+    fn options(&self) -> WriteOptions {
+        WriteOptions {
+            address: self.address.map(|address| address as usize),
+            verify: self.verify,
+            incremental: self.incremental,
+        }
+    }
+}
+
+// How long each stage of talking to the device may take before giving up, in seconds
+#[derive(clap::Args, Debug)]
+struct TimeoutArgs {
+    /// With --wait, how long each port of a gang write may take to show up
+    #[arg(long, default_value_t = 60)]
+    open_timeout: u64,
+    /// Catching the ROM after reset, loading and starting the programmer
+    #[arg(long, default_value_t = 60)]
+    sync_timeout: u64,
+    /// Short commands such as reading the flash ID or rebooting
+    #[arg(long, default_value_t = 5)]
+    command_timeout: u64,
+    #[arg(long, default_value_t = 60)]
+    erase_timeout: u64,
+    /// Each acknowledgement of a burnt flash chunk
+    #[arg(long, default_value_t = 10)]
+    chunk_ack_timeout: u64,
+    #[arg(long, default_value_t = 10)]
+    commit_timeout: u64,
+    /// Each chunk of a flash read
+    #[arg(long, default_value_t = 10)]
+    read_timeout: u64,
+}
+
+impl TimeoutArgs {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            open: Duration::from_secs(self.open_timeout),
+            sync: Duration::from_secs(self.sync_timeout),
+            command: Duration::from_secs(self.command_timeout),
+            erase: Duration::from_secs(self.erase_timeout),
+            chunk_ack: Duration::from_secs(self.chunk_ack_timeout),
+            commit: Duration::from_secs(self.commit_timeout),
+            read: Duration::from_secs(self.read_timeout),
+        }
+    }
+}
+
+// Addresses are much easier to give in hex, so accept either
+fn parse_number(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.replace('_', "").parse(),
+    };
+    parsed.map_err(|e| format!("{value} is not a number: {e}"))
+}
+
+fn parse_chip(value: &str) -> Result<&'static ChipProfile, String> {
+    find_chip(value).ok_or_else(|| format!("{value} is not one of {}", chip_names().join(", ")))
+}
+
+// Splits a uart capture into BES frames and prints what each one means
+#[derive(clap::Args, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Decode {
+    /// Raw uart capture, or a capture file made with --record
+    capture_path: std::path::PathBuf,
+    /// Device to pc side of a two channel capture; the first file is then the pc to device side
+    #[arg(long)]
+    rx: Option<std::path::PathBuf>,
+}
+
+// Runs any of the other commands with the device side played back from a capture made with --record
+#[derive(clap::Args, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Replay {
+    capture_path: std::path::PathBuf,
+    /// The command to run, e.g. `write-image --port /dev/ttyUSB0 firmware.bin`
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    command: Vec<String>,
 }
 
 fn main() {
@@ -81,27 +322,133 @@ fn main() {
     let subscriber = tracing_subscriber::fmt()
         // filter spans/events with level TRACE or higher.
         .with_max_level(Level::INFO)
+        // Keep stdout for command output, so reports like `info --json` can be piped
+        .with_writer(std::io::stderr)
         .finish();
     let _ = tracing::subscriber::set_global_default(subscriber);
 
-    match BesTool::parse() {
+    let cli = Cli::parse();
+    let transport = TransportOptions {
+        record: cli.global.record.clone(),
+        ..TransportOptions::default()
+    };
+    run_command(cli, &transport);
+}
+
+// Options given to replay itself that the replayed command line does not agree with
+fn conflicting_global_options(current: &GlobalArgs, replayed: &GlobalArgs) -> Vec<&'static str> {
+    let mut conflicts = vec![];
+    if current.chip.is_some() && current.chip != replayed.chip {
+        conflicts.push("--chip");
+    }
+    if current.baud.is_some() && current.baud != replayed.baud {
+        conflicts.push("--baud");
+    }
+    if current.reset.is_some() && current.reset != replayed.reset {
+        conflicts.push("--reset");
+    }
+    if current.programmer.is_some() && current.programmer != replayed.programmer {
+        conflicts.push("--programmer");
+    }
+    conflicts
+}
+
+fn run_command(cli: Cli, transport: &TransportOptions) {
+    match cli.command {
         BesTool::ListSerialPorts(_) => cmd_list_serial_ports(),
+        BesTool::Info(args) => {
+            cmd_info(
+                &args.port,
+                args.json,
+                args.wait,
+                &cli.global.session(&args.timeouts, transport),
+            );
+        }
         BesTool::SerialMonitor(args) => {
-            cmd_serial_port_monitor(&args.serial_port_path, args.baud_rate, args.wait);
+            cmd_serial_port_monitor(&args.serial_port_path, args.baud_rate, args.wait, transport);
+        }
+        BesTool::WriteImage(args) => {
+            let mut ports = args.port;
+            if let Some(pattern) = &args.port_glob {
+                ports.extend(ports_matching(pattern));
+            }
+            match (ports.as_slice(), &args.port_glob) {
+                ([port], None) => cmd_write_image(
+                    &args.firmware_path,
+                    port,
+                    &args.write.options(),
+                    args.wait,
+                    &cli.global.session(&args.timeouts, transport),
+                ),
+                _ => cmd_gang_write_image(
+                    &args.firmware_path,
+                    &ports,
+                    &args.write.options(),
+                    args.wait,
+                    &cli.global.session(&args.timeouts, transport),
+                ),
+            }
         }
-        BesTool::WriteImage(args) => cmd_write_image(&args.firmware_path, &args.port, args.wait),
         BesTool::ReadImage(args) => cmd_read_image(
             &args.firmware_path,
             &args.port,
             args.offset as usize,
-            args.length as usize,
+            args.length.map(|length| length as usize),
+            args.resume,
             args.wait,
+            &cli.global.session(&args.timeouts, transport),
         ),
         BesTool::WriteImageThenMonitor(args) => cmd_write_image_then_monitor(
             &args.firmware_path,
             &args.port,
+            &args.write.options(),
             args.monitor_baud_rate,
             args.wait,
+            &cli.global.session(&args.timeouts, transport),
+        ),
+        BesTool::Erase(args) => {
+            let range = match (args.all, args.length) {
+                (false, Some(length)) => EraseRange::Range {
+                    offset: args.offset as usize,
+                    length: length as usize,
+                },
+                // clap makes sure one or the other is given
+                _ => EraseRange::All {
+                    confirmed: args.yes,
+                },
+            };
+            cmd_erase(
+                &args.port,
+                &range,
+                args.wait,
+                &cli.global.session(&args.timeouts, transport),
+            );
+        }
+        BesTool::BlankCheck(args) => cmd_blank_check(
+            &args.port,
+            args.offset as usize,
+            args.length.map(|length| length as usize),
+            args.wait,
+            &cli.global.session(&args.timeouts, transport),
         ),
+        BesTool::Decode(args) => cmd_decode(&args.capture_path, &args.rx),
+        BesTool::Replay(args) => {
+            let replayed =
+                Cli::parse_from(std::iter::once("bestool".to_owned()).chain(args.command));
+            // The traffic only matches if the session is replayed with the options it was recorded with
+            let conflicts = conflicting_global_options(&cli.global, &replayed.global);
+            if !conflicts.is_empty() {
+                error!(
+                    "Options given to replay do not match the recorded command line: {}",
+                    conflicts.join(", ")
+                );
+                std::process::exit(1);
+            }
+            let transport = TransportOptions {
+                replay: Some(args.capture_path),
+                ..transport.clone()
+            };
+            run_command(replayed, &transport);
+        }
     }
 }
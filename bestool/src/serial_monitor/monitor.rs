@@ -1,10 +1,10 @@
-use serialport::SerialPort;
+use crate::beslink::BesTransport;
 use std::cmp::min;
 use std::error::Error;
 use std::io::{stdout, Read, Write};
 use std::time::Duration;
 
-pub fn run_serial_monitor(mut port: Box<dyn SerialPort>) -> Result<(), Box<dyn Error>> {
+pub fn run_serial_monitor(mut port: Box<dyn BesTransport>) -> Result<(), Box<dyn Error>> {
     // Until exit, read from the port and display; and send back anything the user types to the uart
     // Except we catch an exit combo
     port.set_timeout(Duration::from_millis(1000))
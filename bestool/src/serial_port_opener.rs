@@ -1,26 +1,442 @@
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use serialport::SerialPort;
-use tracing::info;
+use crate::beslink::{
+    BESLinkError, BesTransport, Deadline, RecordingTransport, ReplayTransport, TcpTransport,
+};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use tracing::{error, info, warn};
 
-pub fn open_serial_port_with_wait(
+// What happens to the traffic of every transport a command opens, worked out from the command line
+#[derive(Debug, Default, Clone)]
+pub struct TransportOptions {
+    // Every transport is recorded to this capture file
+    pub record: Option<PathBuf>,
+    // Set by the replay command; every transport plays back this capture instead of the named port
+    pub replay: Option<PathBuf>,
+    // Set when several ports are driven at once, so each gets its own capture file
+    pub record_per_port: bool,
+}
+
+// capture.txt recording /dev/ttyUSB0 becomes capture-dev_ttyUSB0.txt
+fn per_port_capture_path(capture_path: &Path, port_path: &str) -> PathBuf {
+    let port: String = port_path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut file_name = capture_path.file_stem().unwrap_or_default().to_owned();
+    file_name.push(format!("-{}", port.trim_matches('_')));
+    if let Some(extension) = capture_path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    capture_path.with_file_name(file_name)
+}
+
+// Ways of naming a port that survive replugging it
+#[derive(Debug, PartialEq)]
+enum PortMatcher {
+    // The only USB serial port
+    Auto,
+    // usb:VID or usb:VID:PID, in hex
+    Usb { vid: u16, pid: Option<u16> },
+    // serial:<USB serial number>
+    SerialNumber(String),
+}
+
+impl PortMatcher {
+    // None for anything that should be opened as a path
+    fn parse(port_path: &str) -> Option<Result<Self, String>> {
+        if port_path == "auto" {
+            return Some(Ok(PortMatcher::Auto));
+        }
+        if let Some(serial_number) = port_path.strip_prefix("serial:") {
+            return Some(Ok(PortMatcher::SerialNumber(serial_number.to_owned())));
+        }
+        let ids = port_path.strip_prefix("usb:")?;
+        let parse_id = |id: &str| {
+            u16::from_str_radix(id, 16)
+                .map_err(|_| format!("{id} in {port_path} is not a hex USB ID"))
+        };
+        Some(match ids.split_once(':') {
+            Some((vid, pid)) => parse_id(vid).and_then(|vid| {
+                Ok(PortMatcher::Usb {
+                    vid,
+                    pid: Some(parse_id(pid)?),
+                })
+            }),
+            None => parse_id(ids).map(|vid| PortMatcher::Usb { vid, pid: None }),
+        })
+    }
+
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        let SerialPortType::UsbPort(usb) = &port.port_type else {
+            return false;
+        };
+        match self {
+            PortMatcher::Auto => true,
+            PortMatcher::Usb { vid, pid } => {
+                usb.vid == *vid && pid.is_none_or(|pid| usb.pid == pid)
+            }
+            PortMatcher::SerialNumber(serial_number) => {
+                usb.serial_number.as_deref() == Some(serial_number.as_str())
+            }
+        }
+    }
+}
+
+pub fn describe_port_type(port_type: &SerialPortType) -> String {
+    match port_type {
+        SerialPortType::UsbPort(info) => match &info.serial_number {
+            Some(serial_number) => format!(
+                "USB 0x{:04X}:0x{:04X} serial {serial_number}",
+                info.vid, info.pid
+            ),
+            None => format!("USB 0x{:04X}:0x{:04X}", info.vid, info.pid),
+        },
+        SerialPortType::PciPort => "PCI".to_owned(),
+        SerialPortType::BluetoothPort => "Bluetooth".to_owned(),
+        SerialPortType::Unknown => "Unknown".to_owned(),
+    }
+}
+
+// Whether to try a port that is not there yet again; a deadline stops the waiting
+fn keep_waiting(wait_for_port: bool, deadline: Option<&Deadline>) -> Result<bool, BESLinkError> {
+    if let Some(deadline) = deadline {
+        deadline.check()?;
+    }
+    Ok(wait_for_port)
+}
+
+// Turns auto, usb:... and serial:... into the path of the one port they match; other names are passed through.
+// With wait_for_port, keeps looking until the device shows up
+fn resolve_port_path(
+    port_path: &str,
+    wait_for_port: bool,
+    deadline: Option<&Deadline>,
+) -> Result<String, BESLinkError> {
+    let matcher = match PortMatcher::parse(port_path) {
+        None => return Ok(port_path.to_owned()),
+        Some(Ok(matcher)) => matcher,
+        Some(Err(reason)) => return Err(BESLinkError::BadPort { reason }),
+    };
+    loop {
+        let ports = serialport::available_ports().unwrap_or_default();
+        let candidates: Vec<&SerialPortInfo> =
+            ports.iter().filter(|port| matcher.matches(port)).collect();
+        match candidates.as_slice() {
+            [port] => {
+                info!("Using {} for {port_path}", port.port_name);
+                return Ok(port.port_name.clone());
+            }
+            [] if keep_waiting(wait_for_port, deadline)? => {}
+            [] => {
+                return Err(BESLinkError::BadPort {
+                    reason: format!(
+                        "No serial port matches {port_path}; available ports: {}",
+                        describe_ports(ports.iter())
+                    ),
+                });
+            }
+            _ => {
+                return Err(BESLinkError::BadPort {
+                    reason: format!(
+                        "{} serial ports match {port_path}, pick one with usb:VID:PID, serial:NUMBER or its path: {}",
+                        candidates.len(),
+                        describe_ports(candidates.into_iter())
+                    ),
+                });
+            }
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn describe_ports<'a>(ports: impl Iterator<Item = &'a SerialPortInfo>) -> String {
+    let described: Vec<String> = ports
+        .map(|port| {
+            format!(
+                "{} [{}]",
+                port.port_name,
+                describe_port_type(&port.port_type)
+            )
+        })
+        .collect();
+    if described.is_empty() {
+        "none".to_owned()
+    } else {
+        described.join(", ")
+    }
+}
+
+// Serial ports whose name matches a pattern where * is any run of characters and ? any one character
+pub fn ports_matching(pattern: &str) -> Vec<String> {
+    let mut ports: Vec<String> = match serialport::available_ports() {
+        Ok(ports) => ports.into_iter().map(|port| port.port_name).collect(),
+        Err(e) => {
+            warn!("Could not list ports due to {e:?}");
+            vec![]
+        }
+    };
+    ports.retain(|port| wildcard_match(pattern.as_bytes(), port.as_bytes()));
+    ports.sort();
+    ports
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], text)
+                || (!text.is_empty() && wildcard_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => wildcard_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+fn open_serial_port_with_wait(
     port_path: &str,
     baud_rate: u32,
     wait_for_port: bool,
-) -> Box<dyn SerialPort> {
+    deadline: Option<&Deadline>,
+) -> Result<Box<dyn SerialPort>, BESLinkError> {
     // If wait for port is true, we handle it not being openable by retrying while waiting for it
+    let port_path = &resolve_port_path(port_path, wait_for_port, deadline)?;
     info!("Opening {port_path} @ {baud_rate}");
     loop {
         let serial_port = serialport::new(port_path, baud_rate);
         match serial_port.open() {
-            Ok(port) => return port,
-            Err(_) => {
+            Ok(port) => return Ok(port),
+            Err(e) => {
                 //Port didnt open
-                if !wait_for_port {
-                    panic!("Unable to open requested Serial Port");
+                if !keep_waiting(wait_for_port, deadline)? {
+                    return Err(BESLinkError::BadPort {
+                        reason: format!("Unable to open {port_path}: {e}"),
+                    });
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+// For commands driving a single port, where there is nothing left to do if it cannot be opened
+pub fn open_transport_with_wait(
+    port_path: &str,
+    baud_rate: u32,
+    wait_for_port: bool,
+    options: &TransportOptions,
+) -> Box<dyn BesTransport> {
+    match open_transport(port_path, baud_rate, wait_for_port, None, options) {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Ports given as tcp://host:port are a raw socket bridge to a remote uart,
+// replay:<capture> plays back a capture made with --record; anything else is a local serial port.
+// Without a deadline, wait_for_port waits for as long as it takes
+pub fn open_transport(
+    port_path: &str,
+    baud_rate: u32,
+    wait_for_port: bool,
+    deadline: Option<&Deadline>,
+    options: &TransportOptions,
+) -> Result<Box<dyn BesTransport>, BESLinkError> {
+    let transport = open_unrecorded_transport(
+        port_path,
+        baud_rate,
+        wait_for_port,
+        deadline,
+        options.replay.as_deref(),
+    )?;
+    match &options.record {
+        Some(capture_path) => {
+            let capture_path = if options.record_per_port {
+                per_port_capture_path(capture_path, port_path)
+            } else {
+                capture_path.clone()
+            };
+            info!("Recording session to {}", capture_path.display());
+            match RecordingTransport::new(transport, &capture_path) {
+                Ok(recorder) => Ok(Box::new(recorder)),
+                Err(e) => Err(BESLinkError::BadPort {
+                    reason: format!("Unable to create capture file: {e}"),
+                }),
+            }
+        }
+        None => Ok(transport),
+    }
+}
+
+fn open_unrecorded_transport(
+    port_path: &str,
+    baud_rate: u32,
+    wait_for_port: bool,
+    deadline: Option<&Deadline>,
+    replay: Option<&Path>,
+) -> Result<Box<dyn BesTransport>, BESLinkError> {
+    let replay_path = replay.or_else(|| port_path.strip_prefix("replay:").map(Path::new));
+    if let Some(capture_path) = replay_path {
+        info!(
+            "Replaying capture {} in place of {port_path}",
+            capture_path.display()
+        );
+        return match ReplayTransport::open(capture_path) {
+            Ok(replay) => Ok(Box::new(replay)),
+            Err(e) => Err(BESLinkError::BadPort {
+                reason: format!("Unable to load capture {}: {e}", capture_path.display()),
+            }),
+        };
+    }
+    let Some(address) = port_path.strip_prefix("tcp://") else {
+        return Ok(Box::new(open_serial_port_with_wait(
+            port_path,
+            baud_rate,
+            wait_for_port,
+            deadline,
+        )?));
+    };
+    info!("Connecting to {address}");
+    loop {
+        match TcpTransport::connect(address, baud_rate) {
+            Ok(transport) => return Ok(Box::new(transport)),
+            Err(e) => {
+                if !keep_waiting(wait_for_port, deadline)? {
+                    return Err(BESLinkError::BadPort {
+                        reason: format!("Unable to connect to {address}: {e}"),
+                    });
                 }
             }
         }
         std::thread::sleep(Duration::from_millis(250));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::beslink::simulator::DeviceSimulator;
+    use crate::beslink::{
+        helper_sync_and_load_programmer, load_capture, BESLinkError, BesTransport, CaptureEvent,
+        ProgrammingOptions, RecordingTransport, Timeouts, BES_PROGRAMMING_BAUDRATE,
+    };
+    use crate::serial_port_opener::{
+        open_transport, per_port_capture_path, resolve_port_path, wildcard_match, PortMatcher,
+        TransportOptions,
+    };
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match(b"/dev/ttyUSB*", b"/dev/ttyUSB12"));
+        assert!(wildcard_match(b"COM?", b"COM3"));
+        assert!(!wildcard_match(b"COM?", b"COM13"));
+        assert!(!wildcard_match(b"/dev/ttyUSB*", b"/dev/ttyACM0"));
+    }
+    #[test]
+    fn test_port_matchers() {
+        let ch340 = SerialPortInfo {
+            port_name: "/dev/ttyUSB0".to_owned(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x1A86,
+                pid: 0x7523,
+                serial_number: Some("ABC123".to_owned()),
+                manufacturer: None,
+                product: None,
+            }),
+        };
+        let uart = SerialPortInfo {
+            port_name: "/dev/ttyS0".to_owned(),
+            port_type: SerialPortType::Unknown,
+        };
+        let matcher = |port_path| PortMatcher::parse(port_path).unwrap().unwrap();
+        assert!(matcher("auto").matches(&ch340));
+        assert!(!matcher("auto").matches(&uart));
+        assert!(matcher("usb:1a86:7523").matches(&ch340));
+        assert!(matcher("usb:1a86").matches(&ch340));
+        assert!(!matcher("usb:10c4:ea60").matches(&ch340));
+        assert!(matcher("serial:ABC123").matches(&ch340));
+        assert!(!matcher("serial:ABC").matches(&ch340));
+        assert!(PortMatcher::parse("usb:xyz").unwrap().is_err());
+        assert!(PortMatcher::parse("/dev/ttyUSB0").is_none());
+        // Reported as an error rather than a panic, so gang writes can carry on with the other ports
+        assert!(matches!(
+            resolve_port_path("usb:xyz", false, None),
+            Err(BESLinkError::BadPort { .. })
+        ));
+        assert_eq!(
+            resolve_port_path("/dev/ttyUSB0", false, None).unwrap(),
+            "/dev/ttyUSB0"
+        );
+    }
+    #[test]
+    fn test_per_port_capture_path() {
+        assert_eq!(
+            per_port_capture_path(Path::new("logs/capture.txt"), "/dev/ttyUSB0"),
+            PathBuf::from("logs/capture-dev_ttyUSB0.txt")
+        );
+    }
+    #[test]
+    fn test_open_transport_replays_and_records() {
+        let dir = std::env::temp_dir().join(format!("bestool-opener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let timeouts = Timeouts::default();
+        let replay_path = dir.join("replay.txt");
+        {
+            let sim: Box<dyn BesTransport> = Box::new(DeviceSimulator::new());
+            let mut recorder = RecordingTransport::new(sim, &replay_path).unwrap();
+            helper_sync_and_load_programmer(
+                &mut recorder,
+                &ProgrammingOptions::default(),
+                &timeouts,
+            )
+            .unwrap();
+        }
+
+        // The named port does not exist, the capture stands in for it
+        let options = TransportOptions {
+            record: Some(dir.join("record.txt")),
+            replay: Some(replay_path.clone()),
+            record_per_port: true,
+        };
+        {
+            let mut transport = open_transport(
+                "/dev/ttyBES9",
+                BES_PROGRAMMING_BAUDRATE,
+                false,
+                None,
+                &options,
+            )
+            .unwrap();
+            helper_sync_and_load_programmer(
+                &mut transport,
+                &ProgrammingOptions::default(),
+                &timeouts,
+            )
+            .unwrap();
+        }
+        // Recorded under the port's own name, with the same traffic as the capture played back
+        let sent = |path: &Path| -> Vec<u8> {
+            load_capture(path)
+                .unwrap()
+                .into_iter()
+                .filter_map(|(_, event)| match event {
+                    CaptureEvent::Tx(data) => Some(data),
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        };
+        assert_eq!(
+            sent(&dir.join("record-dev_ttyBES9.txt")),
+            sent(&replay_path)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}